@@ -1,18 +1,29 @@
 use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
 
 extern crate wavefront_converter_rs;
-use wavefront_converter_rs::process_directory;
+use wavefront_converter_rs::process_directory_with_options;
+use wavefront_converter_rs::conversion::{ConversionOptions, ConversionError};
+use wavefront_converter_rs::dedupe::{find_duplicates, DedupeScope, DEFAULT_DEDUPE_TOLERANCE};
+use wavefront_converter_rs::modelfactory::FILE_VERSION_NUMBER;
+use wavefront_converter_rs::samples;
+use wavefront_converter_rs::selftest::run_self_test;
 
 fn main() {
 
-    // First arg is executable path, verify second is present
+    // First arg is executable path; the rest is either a subcommand or a directory to convert
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Relative file name must be provided as the first argument");
-        return
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("dedupe-report") => dedupe_report(&args[2..]),
+        Some("selftest") => selftest(),
+        Some("generate-samples") => generate_samples(&args[2..]),
+        _ => convert(&args[1..])
     }
+}
 
-    let file_name = &args[1];
+/// Resolve a path given relative to the current directory
+fn resolve_path(file_name: &str) -> PathBuf {
     let mut input_path = env::current_dir().unwrap();
     for segment in file_name.split('/') {
         if segment == "." {
@@ -21,8 +32,135 @@ fn main() {
             input_path.push(segment);
         }
     }
+    input_path
+}
+
+/// Resolve a path given relative to the current directory, where "." is the current directory
+/// itself rather than its parent as in resolve_path
+fn resolve_relative_path(file_name: &str) -> PathBuf {
+    let current_directory = env::current_dir().unwrap();
+    if file_name == "." {
+        current_directory
+    } else {
+        current_directory.join(file_name)
+    }
+}
+
+fn convert(args: &[String]) {
+    let mobile = args.iter().any(|arg| arg == "--mobile");
+    let file_name = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(name) => name,
+        None => {
+            println!("Relative file name must be provided as the first argument");
+            return
+        }
+    };
+    let input_path = resolve_path(file_name);
 
     let output_path = env::current_dir().unwrap();
     let collisions_path = env::current_dir().unwrap();
-    process_directory(&input_path, &output_path, Some(&collisions_path));
+    let mut options = ConversionOptions::new(output_path);
+    options.collisions_dst_path = Some(collisions_path);
+    options.mobile = mobile;
+    process_directory_with_options(&input_path, &options);
+}
+
+/// wc dedupe-report <dir> [--tolerance <distance>] [--sources | --outputs]
+fn dedupe_report(args: &[String]) {
+    let mut directory: Option<PathBuf> = None;
+    let mut tolerance = DEFAULT_DEDUPE_TOLERANCE;
+    let mut scope = DedupeScope::default();
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        if arg == "--sources" {
+            scope = DedupeScope::Sources;
+        } else if arg == "--outputs" {
+            scope = DedupeScope::Outputs;
+        } else if arg == "--tolerance" {
+            tolerance = match args_iter.next().and_then(|value| value.parse::<f32>().ok()) {
+                Some(value) if value > 0.0 => value,
+                _ => {
+                    println!("--tolerance must be followed by a positive number");
+                    return
+                }
+            };
+        } else {
+            directory = Some(resolve_relative_path(arg));
+        }
+    }
+    let directory = match directory {
+        Some(directory) => directory,
+        None => {
+            println!("Relative directory name must be provided after dedupe-report");
+            return
+        }
+    };
+
+    let report = match find_duplicates(&directory, tolerance, scope) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("{}", e);
+            return
+        }
+    };
+    println!("Scanned {} models in {:?}", report.assets_scanned, directory);
+    for path in report.skipped_files.iter() {
+        println!("Skipped unreadable file: {}", path.display());
+    }
+    if report.duplicate_groups.is_empty() {
+        println!("No likely duplicates found");
+        return
+    }
+    println!("Likely duplicates ({} groups):", report.duplicate_groups.len());
+    for group in report.duplicate_groups.iter() {
+        println!(" {:016x}:", group.geometry_hash);
+        for asset in group.assets.iter() {
+            println!("  {}", asset);
+        }
+    }
+}
+
+/// wc selftest - exits with a non-zero status if any case fails
+fn selftest() {
+    println!("Running self-test (file version {})", FILE_VERSION_NUMBER);
+    let results = run_self_test();
+    for result in results.iter() {
+        if result.passed() {
+            println!(" PASS {}", result.name);
+        } else {
+            println!(" FAIL {}", result.name);
+            for failure in result.failures.iter() {
+                println!("  {}", failure);
+            }
+        }
+    }
+    let passed_count = results.iter().filter(|result| result.passed()).count();
+    println!("{} of {} cases passed", passed_count, results.len());
+    if passed_count < results.len() {
+        std::process::exit(1);
+    }
+}
+
+/// wc generate-samples <dir> [--force]
+fn generate_samples(args: &[String]) {
+    let force = args.iter().any(|arg| arg == "--force");
+    let directory = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(name) => resolve_relative_path(name),
+        None => {
+            println!("Relative directory name must be provided after generate-samples");
+            return
+        }
+    };
+    match samples::generate_samples(&directory, force) {
+        Ok(files_written) => {
+            println!("Files written:");
+            for path in files_written.iter() {
+                println!(" {}", path.display());
+            }
+        },
+        Err(ConversionError::Io(_, e)) if e.kind() == ErrorKind::AlreadyExists => {
+            println!("{:?} is not empty; use --force to write the samples into it anyway", directory)
+        },
+        Err(e) => println!("{}", e)
+    }
 }
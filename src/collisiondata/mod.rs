@@ -1,14 +1,21 @@
 use std::io::Write;
-use std::fs::File;
 use std::fmt::Debug;
 
 use crate::modelfactory::FILE_VERSION_NUMBER;
+use crate::conversion::ConversionError;
 
 pub const WALL_NORMAL_ELEVATION_MIN: f32 = -0.0873; // about 5 degrees
 pub const WALL_NORMAL_ELEVATION_MAX: f32 = 0.0873;
 pub const SLIDE_NORMAL_ELEVATION_MIN: f32 = -0.6981; // about 50 degrees
 pub const SLIDE_NORMAL_ELEVATION_MAX: f32 = 0.6981;
 
+pub const CLASSIFICATION_CODE_TRACTION: u32 = 0;
+pub const CLASSIFICATION_CODE_SLIDING: u32 = 1;
+pub const CLASSIFICATION_CODE_WALL: u32 = 2;
+pub const CLASSIFICATION_CODE_CLIMBABLE: u32 = 3;
+pub const CLASSIFICATION_CODE_CUSTOM_BASE: u32 = 256; // codes below this are reserved for built-in kinds
+pub const MAX_CUSTOM_CLASSIFICATION_ID: u32 = u32::MAX - CLASSIFICATION_CODE_CUSTOM_BASE;
+
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct Vec3 {
@@ -22,6 +29,14 @@ impl Vec3 {
         self.x * other.x + self.y * other.y + self.z * other.z
     }
 
+    pub fn cross(&self, other: &Vec3) -> Vec3 {
+        Vec3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x
+        }
+    }
+
     pub fn len(&self) -> f32 {
         (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
     }
@@ -74,8 +89,92 @@ pub struct Surface {
     pub normal: Vec3
 }
 
+impl Surface {
+    /// The normal implied by the order of the points (counter-clockwise when viewed from the front)
+    pub fn winding_normal(&self) -> Vec3 {
+        (self.point_1 - self.point_0).cross(&(self.point_2 - self.point_0)).normalise()
+    }
+}
+
+/// The kind of collision geometry a polygon is turned into. Built-in kinds get their own section
+/// in the collision file, while custom kinds (declared in wc.toml) share a section with each surface
+/// tagged by its code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Classification {
+    Traction,
+    Sliding,
+    Wall,
+    Climbable,
+    Custom(u32)
+}
+
+impl Classification {
+    pub fn from_normal_elevation(elevation: f32) -> Classification {
+        if elevation > WALL_NORMAL_ELEVATION_MIN && elevation < WALL_NORMAL_ELEVATION_MAX {
+            Classification::Wall
+        } else if elevation > SLIDE_NORMAL_ELEVATION_MIN && elevation < SLIDE_NORMAL_ELEVATION_MAX {
+            Classification::Sliding
+        } else {
+            Classification::Traction
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Classification> {
+        match name {
+            "traction" => Some(Classification::Traction),
+            "sliding" => Some(Classification::Sliding),
+            "wall" => Some(Classification::Wall),
+            "climbable" => Some(Classification::Climbable),
+            _ => None
+        }
+    }
+
+    pub fn from_code(code: u32) -> Classification {
+        match code {
+            CLASSIFICATION_CODE_TRACTION => Classification::Traction,
+            CLASSIFICATION_CODE_SLIDING => Classification::Sliding,
+            CLASSIFICATION_CODE_WALL => Classification::Wall,
+            CLASSIFICATION_CODE_CLIMBABLE => Classification::Climbable,
+            _ if code >= CLASSIFICATION_CODE_CUSTOM_BASE => Classification::Custom(code - CLASSIFICATION_CODE_CUSTOM_BASE),
+            _ => panic!("Unknown classification code: {}", code)
+        }
+    }
+
+    pub fn to_code(&self) -> u32 {
+        match self {
+            Classification::Traction => CLASSIFICATION_CODE_TRACTION,
+            Classification::Sliding => CLASSIFICATION_CODE_SLIDING,
+            Classification::Wall => CLASSIFICATION_CODE_WALL,
+            Classification::Climbable => CLASSIFICATION_CODE_CLIMBABLE,
+            Classification::Custom(id) => CLASSIFICATION_CODE_CUSTOM_BASE + id
+        }
+    }
+}
+
+/// Surfaces belonging to a custom classification, tagged with the classification code
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct ClassifiedSurface {
+    pub classification_code: u32,
+    pub surface: Surface
+}
+
+impl ClassifiedSurface {
+    pub fn new(classification: Classification, surface: Surface) -> ClassifiedSurface {
+        ClassifiedSurface {
+            classification_code: classification.to_code(),
+            surface
+        }
+    }
+
+    pub fn classification(&self) -> Classification {
+        Classification::from_code(self.classification_code)
+    }
+}
+
 /// Walls are defined by 2 points which specify opposite corners of a rectangle, plus a normal for
-/// convenience
+/// convenience. The normal always points to the right of the bottom edge (bottom-left to top-right)
+/// when viewed from above, so runtime code can rely on either the corner order or the normal.
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct Wall {
@@ -102,6 +201,50 @@ impl Wall {
             normal: normal_direction.normalise()
         }
     }
+
+    /// Make a wall from two opposite corners, swapping the horizontal positions of the corners if
+    /// needed so that the normal faces the same side as the given facing direction (typically the
+    /// normal of the source face). Falls back to the facing direction if the corners are
+    /// horizontally coincident.
+    pub fn from_corners_facing(bottom_left: Vec3, top_right: Vec3, facing: Vec3) -> Wall {
+        let facing = Vec3 { x: facing.x, y: 0.0, z: facing.z };
+        let wall = Wall::from_bottom_left_to_top_right(bottom_left, top_right);
+        if wall.normal.len() == 0.0 {
+            return Wall {
+                bottom_left,
+                top_right,
+                normal: facing.normalise()
+            };
+        }
+        if wall.normal.dot(&facing) >= 0.0 {
+            return wall;
+        }
+        Wall::from_bottom_left_to_top_right(
+            Vec3 { x: top_right.x, y: bottom_left.y, z: top_right.z },
+            Vec3 { x: bottom_left.x, y: top_right.y, z: bottom_left.z })
+    }
+
+    pub fn get_bottom_left(&self) -> &Vec3 {
+        &self.bottom_left
+    }
+
+    pub fn get_top_right(&self) -> &Vec3 {
+        &self.top_right
+    }
+
+    pub fn get_normal(&self) -> &Vec3 {
+        &self.normal
+    }
+
+    /// Whether the normal faces the same side as the given face normal, ignoring the vertical
+    /// component (always true if the face normal is vertical)
+    pub fn normal_agrees_with(&self, face_normal: &Vec3) -> bool {
+        let horizontal_face_normal = Vec3 { x: face_normal.x, y: 0.0, z: face_normal.z };
+        if horizontal_face_normal.len() < 0.001 {
+            return true;
+        }
+        self.normal.dot(&horizontal_face_normal) > 0.0
+    }
 }
 
 pub struct CollisionData {
@@ -111,7 +254,14 @@ pub struct CollisionData {
     pub extent_z: [f32; 2],
     pub traction_surfaces: Vec<Surface>,
     pub sliding_surfaces: Vec<Surface>,
-    pub walls: Vec<Wall>
+    pub walls: Vec<Wall>,
+    pub climbable_surfaces: Vec<Surface>,
+    pub custom_surfaces: Vec<ClassifiedSurface>,
+
+    /// Each wall added with add_wall, paired with the winding normal of the face(s) it was made
+    /// from. Walls are looked up here by value, so changes made directly to walls can't pair a
+    /// wall with another wall's faces.
+    wall_face_normals: Vec<(Wall, Vec3)>
 }
 
 impl CollisionData {
@@ -124,7 +274,10 @@ impl CollisionData {
             extent_z: [0.0, 0.0],
             traction_surfaces: vec![],
             sliding_surfaces: vec![],
-            walls: vec![]
+            walls: vec![],
+            climbable_surfaces: vec![],
+            custom_surfaces: vec![],
+            wall_face_normals: vec![]
         }
     }
 
@@ -132,6 +285,13 @@ impl CollisionData {
         &self.model_name
     }
 
+    /// Add a wall, keeping the normal implied by the winding order of the face(s) it was made from
+    /// so that find_misoriented_walls can check it
+    pub fn add_wall(&mut self, wall: Wall, face_winding_normal: Vec3) {
+        self.walls.push(wall);
+        self.wall_face_normals.push((wall, face_winding_normal));
+    }
+
     pub fn remove_wall_duplicates(&mut self) {
         if self.walls.len() < 2 {
             return;
@@ -167,6 +327,19 @@ impl CollisionData {
         }
     }
 
+    /// Verification pass over the walls, returning the indices of any whose normal disagrees with
+    /// the winding order of the face(s) it was made from. Only walls added with add_wall can be
+    /// checked; the source faces aren't stored in collision files, so nothing is found in data
+    /// read back with from_bytes.
+    pub fn find_misoriented_walls(&self) -> Vec<usize> {
+        self.walls.iter()
+            .enumerate()
+            .filter(|(_, wall)| self.wall_face_normals.iter()
+                .any(|(added_wall, face_normal)| added_wall == *wall && !wall.normal_agrees_with(face_normal)))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
     pub fn find_extents(&mut self) {
         let mut x_min = 0f32;
         let mut x_max = 0f32;
@@ -221,6 +394,53 @@ impl CollisionData {
             }
         }
 
+        for surface in self.climbable_surfaces.iter() {
+            for point in [&surface.point_0, &surface.point_1, &surface.point_2].iter() {
+                if point.x < x_min {
+                    x_min = point.x;
+                }
+                if point.x > x_max {
+                    x_max = point.x;
+                }
+                if point.y < y_min {
+                    y_min = point.y;
+                }
+                if point.y > y_max {
+                    y_max = point.y;
+                }
+                if point.z < z_min {
+                    z_min = point.z;
+                }
+                if point.z > z_max {
+                    z_max = point.z;
+                }
+            }
+        }
+
+        for custom_surface in self.custom_surfaces.iter() {
+            let surface = &custom_surface.surface;
+            for point in [&surface.point_0, &surface.point_1, &surface.point_2].iter() {
+                if point.x < x_min {
+                    x_min = point.x;
+                }
+                if point.x > x_max {
+                    x_max = point.x;
+                }
+                if point.y < y_min {
+                    y_min = point.y;
+                }
+                if point.y > y_max {
+                    y_max = point.y;
+                }
+                if point.z < z_min {
+                    z_min = point.z;
+                }
+                if point.z > z_max {
+                    z_max = point.z;
+                }
+            }
+        }
+
         for wall in self.walls.iter() {
             for point in [&wall.bottom_left, &wall.bottom_left].iter() {
                 if point.x < x_min {
@@ -254,7 +474,7 @@ impl CollisionData {
 
     /// # Safety
     /// Should be safe to use - current self should have well-formed data Vecs
-    pub unsafe fn write_data_to_file(&self, file: &mut File) -> std::io::Result<()> {
+    pub unsafe fn write_data_to_file<W: Write>(&self, file: &mut W) -> std::io::Result<()> {
         file.write_all(&FILE_VERSION_NUMBER.to_ne_bytes())?;
         file.write_all(&self.extent_x[0].to_ne_bytes())?;
         file.write_all(&self.extent_x[1].to_ne_bytes())?;
@@ -284,61 +504,104 @@ impl CollisionData {
             file.write_all(&*(surface as *const Wall as *const [u8; 36]))?;
         }
 
+        let surface_count = self.climbable_surfaces.len() as u32;
+        file.write_all(&surface_count.to_ne_bytes())?;
+        assert_eq!(std::mem::size_of::<Surface>(), 48);
+        for surface in self.climbable_surfaces.iter() {
+            file.write_all(&*(surface as *const Surface as *const [u8; 48]))?;
+        }
+
+        let surface_count = self.custom_surfaces.len() as u32;
+        file.write_all(&surface_count.to_ne_bytes())?;
+        assert_eq!(std::mem::size_of::<ClassifiedSurface>(), 52);
+        for surface in self.custom_surfaces.iter() {
+            file.write_all(&*(surface as *const ClassifiedSurface as *const [u8; 52]))?;
+        }
+
         Ok(())
     }
 
     /// # Safety
-    /// Should be safe if processing files generated with the same version of this tool
+    /// Should be safe if processing files generated with the same version of this tool. Panics
+    /// if the data is malformed; use try_from_bytes for data that may not be.
     pub unsafe fn from_bytes(bytes: &[u8]) -> CollisionData {
+        Self::try_from_bytes(bytes).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        let version_ptr = bytes.as_ptr();
-        let version_number = *(version_ptr as *const u32);
+    /// Decode data written by write_data_to_file, failing if it is truncated, has unknown
+    /// classification codes or is from a different version of this tool
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<CollisionData, ConversionError> {
+        let mut offset = 0;
+        let version_number = Self::read_u32(bytes, &mut offset)?;
         if version_number != FILE_VERSION_NUMBER {
-            panic!("Bad file version: expected {} but was {}", FILE_VERSION_NUMBER, version_number);
+            return Err(ConversionError::Parse(format!("Bad file version: expected {} but was {}", FILE_VERSION_NUMBER, version_number)));
         }
 
-        let extent_ptr = version_ptr.add(4);
-        let extent_x_min = *(extent_ptr as *const f32);
-        let extent_ptr = extent_ptr.add(4);
-        let extent_x_max = *(extent_ptr as *const f32);
-        let extent_ptr = extent_ptr.add(4);
-        let extent_y_min = *(extent_ptr as *const f32);
-        let extent_ptr = extent_ptr.add(4);
-        let extent_y_max = *(extent_ptr as *const f32);
-        let extent_ptr = extent_ptr.add(4);
-        let extent_z_min = *(extent_ptr as *const f32);
-        let extent_ptr = extent_ptr.add(4);
-        let extent_z_max = *(extent_ptr as *const f32);
-
-        let surface_count_ptr = extent_ptr.add(4);
-        let surface_count = *(surface_count_ptr as *const u32);
-        let mut traction_surfaces: Vec<Surface> = vec![Surface::default(); surface_count as usize];
-        let surfaces_ptr = surface_count_ptr.add(4);
-        let surface_slice = std::slice::from_raw_parts(surfaces_ptr as *const Surface, surface_count as usize);
-        traction_surfaces.copy_from_slice(surface_slice);
-
-        let surface_count_ptr = surfaces_ptr.add(surface_count as usize * std::mem::size_of::<Surface>());
-        let surface_count = *(surface_count_ptr as *const u32);
-        let mut sliding_surfaces: Vec<Surface> = vec![Surface::default(); surface_count as usize];
-        let surfaces_ptr = surface_count_ptr.add(4);
-        let surface_slice = std::slice::from_raw_parts(surfaces_ptr as *const Surface, surface_count as usize);
-        sliding_surfaces.copy_from_slice(surface_slice);
-
-        let wall_count_ptr = surfaces_ptr.add(surface_count as usize * std::mem::size_of::<Surface>());
-        let wall_count = *(wall_count_ptr as *const u32);
-        let mut walls: Vec<Wall> = vec![Wall::default(); wall_count as usize];
-        let walls_ptr = wall_count_ptr.add(4);
-        let walls_slice = std::slice::from_raw_parts(walls_ptr as *const Wall, wall_count as usize);
-        walls.copy_from_slice(walls_slice);
+        let extent_x = [Self::read_f32(bytes, &mut offset)?, Self::read_f32(bytes, &mut offset)?];
+        let extent_y = [Self::read_f32(bytes, &mut offset)?, Self::read_f32(bytes, &mut offset)?];
+        let extent_z = [Self::read_f32(bytes, &mut offset)?, Self::read_f32(bytes, &mut offset)?];
+
+        let traction_surfaces = Self::read_list(bytes, &mut offset, Self::read_surface)?;
+        let sliding_surfaces = Self::read_list(bytes, &mut offset, Self::read_surface)?;
+        let walls = Self::read_list(bytes, &mut offset, |bytes, offset| Ok(Wall {
+            bottom_left: Self::read_vec3(bytes, offset)?,
+            top_right: Self::read_vec3(bytes, offset)?,
+            normal: Self::read_vec3(bytes, offset)?
+        }))?;
+        let climbable_surfaces = Self::read_list(bytes, &mut offset, Self::read_surface)?;
+        let custom_surfaces = Self::read_list(bytes, &mut offset, |bytes, offset| {
+            let classification_code = Self::read_u32(bytes, offset)?;
+            if classification_code > CLASSIFICATION_CODE_CLIMBABLE && classification_code < CLASSIFICATION_CODE_CUSTOM_BASE {
+                return Err(ConversionError::Parse(format!("Unknown classification code: {}", classification_code)));
+            }
+            Ok(ClassifiedSurface { classification_code, surface: Self::read_surface(bytes, offset)? })
+        })?;
 
-        CollisionData {
+        Ok(CollisionData {
             model_name: String::from(""),
-            extent_x: [extent_x_min, extent_x_max],
-            extent_y: [extent_y_min, extent_y_max],
-            extent_z: [extent_z_min, extent_z_max],
+            extent_x,
+            extent_y,
+            extent_z,
             traction_surfaces,
             sliding_surfaces,
-            walls
-        }
+            walls,
+            climbable_surfaces,
+            custom_surfaces,
+            wall_face_normals: vec![]
+        })
+    }
+
+    /// Read a count followed by that many items
+    fn read_list<T>(bytes: &[u8], offset: &mut usize, read_item: impl Fn(&[u8], &mut usize) -> Result<T, ConversionError>) -> Result<Vec<T>, ConversionError> {
+        let count = Self::read_u32(bytes, offset)?;
+        (0..count).map(|_| read_item(bytes, offset)).collect()
+    }
+
+    fn read_surface(bytes: &[u8], offset: &mut usize) -> Result<Surface, ConversionError> {
+        Ok(Surface {
+            point_0: Self::read_vec3(bytes, offset)?,
+            point_1: Self::read_vec3(bytes, offset)?,
+            point_2: Self::read_vec3(bytes, offset)?,
+            normal: Self::read_vec3(bytes, offset)?
+        })
+    }
+
+    fn read_vec3(bytes: &[u8], offset: &mut usize) -> Result<Vec3, ConversionError> {
+        Ok(Vec3 {
+            x: Self::read_f32(bytes, offset)?,
+            y: Self::read_f32(bytes, offset)?,
+            z: Self::read_f32(bytes, offset)?
+        })
+    }
+
+    fn read_f32(bytes: &[u8], offset: &mut usize) -> Result<f32, ConversionError> {
+        Self::read_u32(bytes, offset).map(f32::from_bits)
+    }
+
+    fn read_u32(bytes: &[u8], offset: &mut usize) -> Result<u32, ConversionError> {
+        let word = bytes.get(*offset..(*offset + 4))
+            .ok_or_else(|| ConversionError::Parse(String::from("Malformed collision data")))?;
+        *offset += 4;
+        Ok(u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
     }
 }
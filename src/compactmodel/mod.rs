@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+
+use half::f16;
+
+use crate::model::{Model, Vertex, Vec3};
+use crate::modelfactory::FILE_VERSION_NUMBER;
+use crate::conversion::ConversionError;
+
+pub const MOBILE_WELD_TOLERANCE: f32 = 0.001;
+pub const MAX_SUBMESH_VERTICES: usize = 65535;
+
+/// A size-optimized vertex: positions are kept at full precision, normals are octahedron-encoded
+/// into two signed normalised 16-bit values and texture coordinates are stored as 16-bit floats.
+/// Use decode_normal and decode_tex_coord (or equivalent shader code) to unpack them.
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct CompactVertex {
+    pub position: Vec3,
+    pub normal: [i16; 2],
+    pub tex_coord: [u16; 2]
+}
+
+vulkano::impl_vertex!(CompactVertex, position, normal, tex_coord);
+
+impl CompactVertex {
+    pub fn from_vertex(vertex: &Vertex) -> CompactVertex {
+        CompactVertex {
+            position: vertex.position,
+            normal: Self::encode_normal(&vertex.normal),
+            tex_coord: [
+                f16::from_f32(vertex.tex_coord[0]).to_bits(),
+                f16::from_f32(vertex.tex_coord[1]).to_bits()
+            ]
+        }
+    }
+
+    pub fn to_vertex(&self) -> Vertex {
+        Vertex::from_components(&self.position, &self.decode_normal(), &self.decode_tex_coord())
+    }
+
+    pub fn decode_normal(&self) -> Vec3 {
+        let mut x = self.normal[0] as f32 / 32767.0;
+        let mut y = self.normal[1] as f32 / 32767.0;
+        let z = 1.0 - x.abs() - y.abs();
+        if z < 0.0 {
+            let folded_x = (1.0 - y.abs()) * x.signum();
+            let folded_y = (1.0 - x.abs()) * y.signum();
+            x = folded_x;
+            y = folded_y;
+        }
+        let length = (x * x + y * y + z * z).sqrt();
+        [x / length, y / length, z / length]
+    }
+
+    pub fn decode_tex_coord(&self) -> [f32; 2] {
+        [f16::from_bits(self.tex_coord[0]).to_f32(), f16::from_bits(self.tex_coord[1]).to_f32()]
+    }
+
+    /// Project the normal onto an octahedron, then unfold the lower half into the corners of the
+    /// unit square
+    fn encode_normal(normal: &Vec3) -> [i16; 2] {
+        let l1_norm = normal[0].abs() + normal[1].abs() + normal[2].abs();
+        if l1_norm == 0.0 {
+            return [0, 0];
+        }
+        let mut x = normal[0] / l1_norm;
+        let mut y = normal[1] / l1_norm;
+        if normal[2] < 0.0 {
+            let folded_x = (1.0 - y.abs()) * x.signum();
+            let folded_y = (1.0 - x.abs()) * y.signum();
+            x = folded_x;
+            y = folded_y;
+        }
+        [(x.clamp(-1.0, 1.0) * 32767.0).round() as i16, (y.clamp(-1.0, 1.0) * 32767.0).round() as i16]
+    }
+
+    /// Vertices with the same key are merged. Positions are snapped to a grid with the given
+    /// spacing, so nearby vertices either side of a grid boundary get different keys.
+    fn weld_key(&self, weld_tolerance: f32) -> [i32; 7] {
+        [
+            (self.position[0] / weld_tolerance).round() as i32,
+            (self.position[1] / weld_tolerance).round() as i32,
+            (self.position[2] / weld_tolerance).round() as i32,
+            self.normal[0] as i32,
+            self.normal[1] as i32,
+            self.tex_coord[0] as i32,
+            self.tex_coord[1] as i32
+        ]
+    }
+}
+
+/// A piece of a compact model small enough to be indexed with 16-bit indices
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct CompactSubmesh {
+    pub vertices: Vec<CompactVertex>,
+    pub face_indices: Vec<u16>
+}
+
+/// A model in the size-optimized format written by the mobile profile
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct CompactModel {
+    pub submeshes: Vec<CompactSubmesh>
+}
+
+/// The file sizes of a model in the standard and size-optimized formats
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeReport {
+    pub model_name: String,
+    pub standard_bytes: usize,
+    pub optimized_bytes: usize
+}
+
+impl SizeReport {
+    /// Compare the size a model would have in the standard format against the given size
+    pub fn for_model(model: &Model, optimized_bytes: usize) -> SizeReport {
+        Self::for_counts(model.get_name(), model.interleaved_vertices.len(), model.face_indices.len(), optimized_bytes)
+    }
+
+    /// As for_model, for a model known only by its vertex and index counts
+    pub(crate) fn for_counts(model_name: &str, vertex_count: usize, index_count: usize, optimized_bytes: usize) -> SizeReport {
+        SizeReport {
+            model_name: String::from(model_name),
+            standard_bytes: 12 + vertex_count * std::mem::size_of::<Vertex>() + index_count * 2,
+            optimized_bytes
+        }
+    }
+
+    pub fn reduction_percent(&self) -> f32 {
+        if self.standard_bytes == 0 {
+            return 0.0;
+        }
+        100.0 * (1.0 - self.optimized_bytes as f32 / self.standard_bytes as f32)
+    }
+}
+
+impl CompactModel {
+    /// Encode and weld the vertices of a model (merging those that snap to the same point on a grid
+    /// with the given spacing), dropping any faces that become degenerate, and split it into
+    /// submeshes whenever the vertex count would exceed the given maximum
+    pub fn from_model(model: &Model, weld_tolerance: f32, max_vertices_per_submesh: usize) -> CompactModel {
+        Self::from_vertices(&model.interleaved_vertices, &model.face_indices, weld_tolerance, max_vertices_per_submesh)
+    }
+
+    /// As from_model, for vertices and face indices of any width, so models too large to be a
+    /// Model can be converted too
+    pub(crate) fn from_vertices<I: Copy + Into<u32>>(interleaved_vertices: &[Vertex], face_indices: &[I], weld_tolerance: f32, max_vertices_per_submesh: usize) -> CompactModel {
+        let mut submeshes: Vec<CompactSubmesh> = vec![];
+        let mut current_submesh = CompactSubmesh::default();
+        let mut index_map: HashMap<[i32; 7], u16> = HashMap::new();
+
+        for face in face_indices.chunks(3) {
+            let vertices: Vec<CompactVertex> = face.iter()
+                .map(|index| CompactVertex::from_vertex(&interleaved_vertices[(*index).into() as usize]))
+                .collect();
+            let keys: Vec<[i32; 7]> = vertices.iter()
+                .map(|vertex| vertex.weld_key(weld_tolerance))
+                .collect();
+            if keys[0] == keys[1] || keys[1] == keys[2] || keys[0] == keys[2] {
+                continue;
+            }
+
+            let new_vertex_count = keys.iter().filter(|key| !index_map.contains_key(*key)).count();
+            if current_submesh.vertices.len() + new_vertex_count > max_vertices_per_submesh {
+                submeshes.push(current_submesh);
+                current_submesh = CompactSubmesh::default();
+                index_map.clear();
+            }
+
+            for (key, vertex) in keys.iter().zip(vertices.iter()) {
+                let index = match index_map.get(key) {
+                    Some(index) => *index,
+                    None => {
+                        let new_index = current_submesh.vertices.len() as u16;
+                        index_map.insert(*key, new_index);
+                        current_submesh.vertices.push(*vertex);
+                        new_index
+                    }
+                };
+                current_submesh.face_indices.push(index);
+            }
+        }
+        if !current_submesh.face_indices.is_empty() {
+            submeshes.push(current_submesh);
+        }
+
+        CompactModel { submeshes }
+    }
+
+    /// Serialise the model, reordering each submesh's faces for vertex cache efficiency and
+    /// compressing its index buffer with meshoptimizer's index codec
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ConversionError> {
+        let mut bytes: Vec<u8> = vec![];
+        bytes.extend_from_slice(&FILE_VERSION_NUMBER.to_ne_bytes());
+        bytes.extend_from_slice(&(self.submeshes.len() as u32).to_ne_bytes());
+        for submesh in self.submeshes.iter() {
+            bytes.extend_from_slice(&(submesh.vertices.len() as u32).to_ne_bytes());
+            for vertex in submesh.vertices.iter() {
+                for component in vertex.position.iter() {
+                    bytes.extend_from_slice(&component.to_ne_bytes());
+                }
+                for component in vertex.normal.iter() {
+                    bytes.extend_from_slice(&component.to_ne_bytes());
+                }
+                for component in vertex.tex_coord.iter() {
+                    bytes.extend_from_slice(&component.to_ne_bytes());
+                }
+            }
+
+            let indices: Vec<u32> = submesh.face_indices.iter().map(|index| *index as u32).collect();
+            let indices = meshopt::optimize_vertex_cache(&indices, submesh.vertices.len());
+            let encoded_indices = meshopt::encode_index_buffer(&indices, submesh.vertices.len())
+                .map_err(|e| ConversionError::Encoding(e.to_string()))?;
+            bytes.extend_from_slice(&(indices.len() as u32).to_ne_bytes());
+            bytes.extend_from_slice(&(encoded_indices.len() as u32).to_ne_bytes());
+            bytes.extend_from_slice(&encoded_indices);
+            let padding = (4 - bytes.len() % 4) % 4;
+            bytes.resize(bytes.len() + padding, 0);
+        }
+        Ok(bytes)
+    }
+
+    /// Decode data produced by to_bytes (panics if the data is malformed or from a different
+    /// version of this tool)
+    pub fn from_bytes(bytes: &[u8]) -> CompactModel {
+        Self::try_from_bytes(bytes).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Decode data produced by to_bytes, failing if it is truncated, malformed or from a
+    /// different version of this tool
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<CompactModel, ConversionError> {
+        let mut offset = 0;
+        let version_number = Self::read_u32(bytes, &mut offset)?;
+        if version_number != FILE_VERSION_NUMBER {
+            return Err(ConversionError::Parse(format!("Bad file version: expected {} but was {}", FILE_VERSION_NUMBER, version_number)));
+        }
+
+        let submesh_count = Self::read_u32(bytes, &mut offset)?;
+        let mut submeshes: Vec<CompactSubmesh> = vec![];
+        for _ in 0..submesh_count {
+            let vertex_count = Self::read_u32(bytes, &mut offset)?;
+            let mut vertices: Vec<CompactVertex> = vec![];
+            for _ in 0..vertex_count {
+                let x = f32::from_bits(Self::read_u32(bytes, &mut offset)?);
+                let y = f32::from_bits(Self::read_u32(bytes, &mut offset)?);
+                let z = f32::from_bits(Self::read_u32(bytes, &mut offset)?);
+                let normal = Self::read_u32(bytes, &mut offset)?.to_ne_bytes();
+                let tex_coord = Self::read_u32(bytes, &mut offset)?.to_ne_bytes();
+                vertices.push(CompactVertex {
+                    position: [x, y, z],
+                    normal: [
+                        i16::from_ne_bytes([normal[0], normal[1]]),
+                        i16::from_ne_bytes([normal[2], normal[3]])
+                    ],
+                    tex_coord: [
+                        u16::from_ne_bytes([tex_coord[0], tex_coord[1]]),
+                        u16::from_ne_bytes([tex_coord[2], tex_coord[3]])
+                    ]
+                });
+            }
+
+            // The codec needs at least one byte per triangle, so checking that keeps a corrupt
+            // index count from allocating more than the file could describe
+            let index_count = Self::read_u32(bytes, &mut offset)? as usize;
+            let encoded_length = Self::read_u32(bytes, &mut offset)? as usize;
+            if encoded_length < index_count / 3 {
+                return Err(Self::malformed());
+            }
+            let encoded_indices = offset.checked_add(encoded_length)
+                .and_then(|encoded_end| bytes.get(offset..encoded_end))
+                .ok_or_else(Self::malformed)?;
+            let face_indices = meshopt::decode_index_buffer::<u16>(encoded_indices, index_count)
+                .map_err(|e| ConversionError::Parse(e.to_string()))?;
+            if face_indices.iter().any(|index| *index as usize >= vertices.len()) {
+                return Err(Self::malformed());
+            }
+            offset += encoded_length;
+            offset += (4 - offset % 4) % 4;
+
+            submeshes.push(CompactSubmesh { vertices, face_indices });
+        }
+
+        Ok(CompactModel { submeshes })
+    }
+
+    fn malformed() -> ConversionError {
+        ConversionError::Parse(String::from("Malformed compact model data"))
+    }
+
+    fn read_u32(bytes: &[u8], offset: &mut usize) -> Result<u32, ConversionError> {
+        let word = bytes.get(*offset..(*offset + 4)).ok_or_else(Self::malformed)?;
+        *offset += 4;
+        Ok(u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::collisiondata::{Classification, MAX_CUSTOM_CLASSIFICATION_ID};
+use crate::conversion::ConversionError;
+
+pub const CONFIG_FILE_NAME: &str = "wc.toml";
+pub const IGNORE_FILE_NAME: &str = ".wcignore";
+
+/// Options read from an optional wc.toml file placed in the source directory
+#[derive(Default, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct WcConfig {
+    pub mobile: bool,
+    pub classification: ClassificationConfig,
+    pub ignore: IgnoreConfig,
+
+    /// The file this was read from, if any, so errors can say where the problem is
+    #[serde(skip)]
+    pub source_path: Option<PathBuf>
+}
+
+/// The [ignore] table, listing glob patterns for objects and files that are parsed but never
+/// exported. Each line of a .wcignore file next to wc.toml is added to both lists.
+#[derive(Default, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct IgnoreConfig {
+    pub objects: Vec<String>,
+    pub files: Vec<String>
+}
+
+/// The [classification] table. Materials and objects map to either a built-in classification
+/// name or the name of a custom category declared in the categories table, e.g.
+///
+/// ```toml
+/// [classification.categories]
+/// ladder = 0
+///
+/// [classification.materials]
+/// ladder = "ladder"
+/// ramp_steep = "traction"
+///
+/// [classification.climbable]
+/// min_elevation = 10.0
+/// max_elevation = 25.0
+/// ```
+#[derive(Default, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ClassificationConfig {
+    pub categories: HashMap<String, u32>,
+    pub materials: HashMap<String, String>,
+    pub objects: HashMap<String, String>,
+    pub climbable: Option<ElevationBand>
+}
+
+/// A range of normal elevations, in degrees above the horizontal
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ElevationBand {
+    pub min_elevation: f32,
+    pub max_elevation: f32
+}
+
+impl WcConfig {
+    /// Read wc.toml from the given directory, or use defaults if there isn't one (panics if the
+    /// file exists but can't be read)
+    pub fn load_from_directory(directory: &PathBuf) -> WcConfig {
+        match WcConfig::try_load_from_directory(directory) {
+            Ok(config) => config,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Read wc.toml and .wcignore from the given directory, using defaults for whichever isn't
+    /// there
+    pub fn try_load_from_directory(directory: &PathBuf) -> Result<WcConfig, ConversionError> {
+        let mut config_file_path: PathBuf = directory.into();
+        config_file_path.push(CONFIG_FILE_NAME);
+        let mut config = if config_file_path.is_file() {
+            let file_contents = fs::read_to_string(&config_file_path)
+                .map_err(|e| ConversionError::Io(config_file_path.clone(), e))?;
+            let mut config: WcConfig = toml::from_str(&file_contents)
+                .map_err(|e| ConversionError::Config(config_file_path.clone(), e.to_string()))?;
+            config.source_path = Some(config_file_path);
+            config
+        } else {
+            WcConfig::default()
+        };
+
+        let mut ignore_file_path: PathBuf = directory.into();
+        ignore_file_path.push(IGNORE_FILE_NAME);
+        if ignore_file_path.is_file() {
+            let file_contents = fs::read_to_string(&ignore_file_path)
+                .map_err(|e| ConversionError::Io(ignore_file_path.clone(), e))?;
+            for (line_index, line) in file_contents.lines().map(|line| line.trim()).enumerate() {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                if let Err(e) = Pattern::new(line) {
+                    return Err(ConversionError::Config(ignore_file_path, format!("Bad ignore pattern {} on line {}: {}", line, line_index + 1, e)));
+                }
+                config.ignore.objects.push(String::from(line));
+                config.ignore.files.push(String::from(line));
+            }
+        }
+
+        config.classification_overrides()?;
+        config.ignore_list()?;
+        Ok(config)
+    }
+
+    /// The path to name in errors about this config
+    fn error_path(&self) -> PathBuf {
+        match &self.source_path {
+            Some(path) => path.clone(),
+            None => PathBuf::from(CONFIG_FILE_NAME)
+        }
+    }
+
+    /// Compile the ignore patterns (errors if any are not valid glob patterns)
+    pub fn ignore_list(&self) -> Result<IgnoreList, ConversionError> {
+        let compile = |patterns: &Vec<String>| -> Result<Vec<Pattern>, ConversionError> {
+            patterns.iter()
+                .map(|pattern| Pattern::new(pattern)
+                    .map_err(|e| ConversionError::Config(self.error_path(), format!("Bad ignore pattern {}: {}", pattern, e))))
+                .collect()
+        };
+        Ok(IgnoreList {
+            object_patterns: compile(&self.ignore.objects)?,
+            file_patterns: compile(&self.ignore.files)?
+        })
+    }
+
+    /// Resolve the names used in the classification tables into classifications (errors if a
+    /// name is neither built-in nor a declared category, if any declared category is invalid, or if
+    /// the climbable band is inverted)
+    pub fn classification_overrides(&self) -> Result<ClassificationOverrides, ConversionError> {
+        for name in self.classification.categories.keys() {
+            self.classification.resolve_name(name, &self.error_path())?;
+        }
+        if let Some(band) = self.classification.climbable {
+            if band.min_elevation > band.max_elevation {
+                return Err(ConversionError::Config(self.error_path(), format!(
+                    "Climbable min_elevation {} is above max_elevation {}", band.min_elevation, band.max_elevation)));
+            }
+        }
+        let resolve = |table: &HashMap<String, String>| -> Result<HashMap<String, Classification>, ConversionError> {
+            table.iter()
+                .map(|(key, name)| Ok((key.clone(), self.classification.resolve_name(name, &self.error_path())?)))
+                .collect()
+        };
+        Ok(ClassificationOverrides {
+            materials: resolve(&self.classification.materials)?,
+            objects: resolve(&self.classification.objects)?,
+            climbable_elevation: self.classification.climbable
+                .map(|band| [band.min_elevation.to_radians(), band.max_elevation.to_radians()])
+        })
+    }
+}
+
+impl ClassificationConfig {
+    /// Categories can't share a name with a built-in classification, and their IDs must leave room
+    /// for the reserved codes below CLASSIFICATION_CODE_CUSTOM_BASE
+    fn resolve_name(&self, name: &str, config_path: &Path) -> Result<Classification, ConversionError> {
+        if let Some(classification) = Classification::from_name(name) {
+            if self.categories.contains_key(name) {
+                return Err(ConversionError::Config(config_path.into(), format!("Category {} has the same name as a built-in classification", name)));
+            }
+            return Ok(classification);
+        }
+        match self.categories.get(name) {
+            Some(id) if *id > MAX_CUSTOM_CLASSIFICATION_ID => Err(ConversionError::Config(config_path.into(),
+                format!("Category {} has ID {}, above the maximum of {}", name, id, MAX_CUSTOM_CLASSIFICATION_ID))),
+            Some(id) => Ok(Classification::Custom(*id)),
+            None => Err(ConversionError::Config(config_path.into(), format!("Unknown classification: {}", name)))
+        }
+    }
+}
+
+/// Classifications to use in place of the normal-based classification. A material override is
+/// more specific than an object override, so it takes precedence. Polygons without an override
+/// are classified by normal elevation, with the climbable band (if any) checked first.
+#[derive(Default, Clone, Debug)]
+pub struct ClassificationOverrides {
+    materials: HashMap<String, Classification>,
+    objects: HashMap<String, Classification>,
+    climbable_elevation: Option<[f32; 2]>
+}
+
+impl ClassificationOverrides {
+    pub fn find(&self, object_name: &str, material_name: Option<&str>) -> Option<Classification> {
+        if let Some(material_name) = material_name {
+            if let Some(classification) = self.materials.get(material_name) {
+                return Some(*classification);
+            }
+        }
+        self.objects.get(object_name).copied()
+    }
+
+    pub fn classify_by_elevation(&self, elevation: f32) -> Classification {
+        if let Some([min_elevation, max_elevation]) = self.climbable_elevation {
+            if elevation >= min_elevation && elevation <= max_elevation {
+                return Classification::Climbable;
+            }
+        }
+        Classification::from_normal_elevation(elevation)
+    }
+}
+
+/// Compiled ignore patterns. File patterns are matched against file names only.
+#[derive(Default, Clone, Debug)]
+pub struct IgnoreList {
+    object_patterns: Vec<Pattern>,
+    file_patterns: Vec<Pattern>
+}
+
+impl IgnoreList {
+    pub fn ignores_object(&self, object_name: &str) -> bool {
+        self.object_patterns.iter().any(|pattern| pattern.matches(object_name))
+    }
+
+    pub fn ignores_file(&self, file_path: &Path) -> bool {
+        match file_path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => self.file_patterns.iter().any(|pattern| pattern.matches(file_name)),
+            None => false
+        }
+    }
+}
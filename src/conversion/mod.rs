@@ -0,0 +1,239 @@
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::config::WcConfig;
+use crate::modelfactory::ModelFactory;
+use crate::compactmodel::SizeReport;
+
+/// Errors that can stop a single source file from being converted
+#[derive(Debug)]
+pub enum ConversionError {
+    Io(PathBuf, std::io::Error),
+    Config(PathBuf, String),
+    Parse(String),
+    Encoding(String),
+    Cancelled
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversionError::Io(path, e) => write!(f, "I/O error for {:?}: {}", path, e),
+            ConversionError::Config(path, message) => write!(f, "Error in config {:?}: {}", path, message),
+            ConversionError::Parse(message) => write!(f, "Parse error: {}", message),
+            ConversionError::Encoding(message) => write!(f, "Encoding error: {}", message),
+            ConversionError::Cancelled => write!(f, "Conversion was cancelled")
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Options for convert_paths. If no config is given, a wc.toml is looked for in the directory of
+/// each source file. The mobile profile (enabled here or in the config) writes size-optimized
+/// .cmdl files in place of .mdl files. Setting the cancellation token from another thread stops
+/// the conversion at the next opportunity; files not yet converted fail with Cancelled, and
+/// nothing is left behind for the file being converted at the time.
+#[derive(Clone, Debug)]
+pub struct ConversionOptions {
+    pub dst_path: PathBuf,
+    pub collisions_dst_path: Option<PathBuf>,
+    pub config: Option<WcConfig>,
+    pub parallel: bool,
+    pub mobile: bool,
+    pub cancellation_token: Option<Arc<AtomicBool>>
+}
+
+impl ConversionOptions {
+    pub fn new(dst_path: PathBuf) -> ConversionOptions {
+        ConversionOptions {
+            dst_path,
+            collisions_dst_path: None,
+            config: None,
+            parallel: false,
+            mobile: false,
+            cancellation_token: None
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        is_cancelled(&self.cancellation_token)
+    }
+}
+
+static NEXT_STAGED_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn is_cancelled(cancellation_token: &Option<Arc<AtomicBool>>) -> bool {
+    match cancellation_token {
+        Some(token) => token.load(Ordering::Relaxed),
+        None => false
+    }
+}
+
+/// Output files are first written to temporary paths alongside their final ones, then moved into
+/// place together by commit. Any that haven't been moved into place are deleted when this is
+/// dropped, so a failed or cancelled conversion doesn't leave partially written files behind. If
+/// moving a file into place fails, the files moved before it are left where they are (they are
+/// complete, but the set of outputs is not). Temporary names include the process ID and a counter,
+/// so conversions running at the same time never stage to the same temporary file.
+#[derive(Default)]
+pub struct StagedFiles {
+    staged_paths: Vec<(PathBuf, PathBuf)>
+}
+
+impl StagedFiles {
+    pub fn new() -> StagedFiles {
+        StagedFiles::default()
+    }
+
+    /// Create the temporary file to be moved to the given path on commit
+    pub fn create(&mut self, path: &PathBuf) -> Result<File, ConversionError> {
+        let staged_file_id = NEXT_STAGED_FILE_ID.fetch_add(1, Ordering::Relaxed);
+        let unique_suffix = format!("{}-{}.tmp", std::process::id(), staged_file_id);
+        let mut temp_path: PathBuf = path.into();
+        let temp_extension = match path.extension() {
+            Some(extension) => format!("{}.{}", extension.to_string_lossy(), unique_suffix),
+            None => unique_suffix
+        };
+        temp_path.set_extension(temp_extension);
+        let file = File::create(&temp_path)
+            .map_err(|e| ConversionError::Io(temp_path.clone(), e))?;
+        self.staged_paths.push((temp_path, path.into()));
+        Ok(file)
+    }
+
+    /// Move all temporary files into place, returning the final paths. Each is only removed from
+    /// the staged list as it is moved, so any left when a move fails are cleaned up on drop.
+    pub fn commit(mut self) -> Result<Vec<PathBuf>, ConversionError> {
+        let mut committed_paths: Vec<PathBuf> = vec![];
+        while !self.staged_paths.is_empty() {
+            let (temp_path, final_path) = self.staged_paths.remove(0);
+            if let Err(e) = fs::rename(&temp_path, &final_path) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(ConversionError::Io(final_path, e));
+            }
+            committed_paths.push(final_path);
+        }
+        Ok(committed_paths)
+    }
+}
+
+impl Drop for StagedFiles {
+    fn drop(&mut self) {
+        for (temp_path, _) in self.staged_paths.iter() {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// What was produced from one source file. Size reports are only generated by the mobile profile.
+/// Models that were parsed but not exported because of the ignore list are named separately.
+/// Problems found in the source that didn't stop the conversion are reported as warnings.
+#[derive(Debug, Default)]
+pub struct ConversionOutput {
+    pub files_written: Vec<PathBuf>,
+    pub size_reports: Vec<SizeReport>,
+    pub ignored_models: Vec<String>,
+    pub warnings: Vec<String>
+}
+
+/// The outcome of converting one source file: the output, or the error that stopped it
+#[derive(Debug)]
+pub struct FileConversionResult {
+    pub source_path: PathBuf,
+    pub result: Result<ConversionOutput, ConversionError>
+}
+
+impl FileConversionResult {
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Convert each of the given files, returning one result per file in the same order. With the
+/// parallel option set, files are shared between a worker thread per available core. Otherwise,
+/// each path is only taken from the iterator once the file before it has been converted.
+pub fn convert_paths<I, P>(paths: I, options: &ConversionOptions) -> Vec<FileConversionResult>
+    where I: IntoIterator<Item = P>, P: Into<PathBuf>
+{
+    if !options.parallel {
+        return paths.into_iter()
+            .map(|path| convert_file(path.into(), options))
+            .collect();
+    }
+    let paths: Vec<PathBuf> = paths.into_iter().map(|path| path.into()).collect();
+    if paths.len() < 2 {
+        return paths.into_iter()
+            .map(|path| convert_file(path, options))
+            .collect();
+    }
+
+    let worker_count = std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+        .min(paths.len());
+    let pending = Mutex::new(paths.into_iter().enumerate());
+    let mut indexed_results: Vec<(usize, FileConversionResult)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..worker_count)
+            .map(|_| scope.spawn(|| {
+                let mut results = vec![];
+                loop {
+                    let next = pending.lock().unwrap().next();
+                    match next {
+                        Some((index, path)) => results.push((index, convert_file(path, options))),
+                        None => break
+                    }
+                }
+                results
+            }))
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    indexed_results.sort_by_key(|(index, _)| *index);
+    indexed_results.into_iter()
+        .map(|(_, result)| result)
+        .collect()
+}
+
+fn convert_file(src_file_path: PathBuf, options: &ConversionOptions) -> FileConversionResult {
+    let result = if options.is_cancelled() {
+        Err(ConversionError::Cancelled)
+    } else {
+        try_convert_file(&src_file_path, options)
+    };
+    FileConversionResult {
+        source_path: src_file_path,
+        result
+    }
+}
+
+fn try_convert_file(src_file_path: &PathBuf, options: &ConversionOptions) -> Result<ConversionOutput, ConversionError> {
+    let config = match &options.config {
+        Some(config) => config.clone(),
+        None => {
+            let mut config_directory: PathBuf = src_file_path.into();
+            config_directory.pop();
+            WcConfig::try_load_from_directory(&config_directory)?
+        }
+    };
+    let ignore_list = config.ignore_list()?;
+    let mut factory = ModelFactory::new(src_file_path.into(), config.classification_overrides()?);
+    if let Some(token) = &options.cancellation_token {
+        factory.set_cancellation_token(token.clone());
+    }
+    let collisions_dst_path = options.collisions_dst_path.as_ref();
+    factory.extract_all_models_from_file(collisions_dst_path.is_some())?;
+    if ignore_list.ignores_file(src_file_path) {
+        return Ok(ConversionOutput {
+            ignored_models: factory.get_model_names(),
+            ..ConversionOutput::default()
+        });
+    }
+    factory.set_ignore_list(ignore_list);
+    factory.export_all(&options.dst_path, collisions_dst_path, options.mobile || config.mobile)
+}
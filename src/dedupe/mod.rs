@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use crate::model::{Model, Vertex, Vec3};
+use crate::compactmodel::CompactModel;
+use crate::config::ClassificationOverrides;
+use crate::conversion::ConversionError;
+use crate::modelfactory::ModelFactory;
+
+pub const DEFAULT_DEDUPE_TOLERANCE: f32 = 0.001;
+
+type Triangle = [Vec3; 3];
+type AssetGeometry = (DedupeAsset, Vec<Triangle>);
+
+/// One model found while scanning; source files can hold several models, so those are named too
+#[derive(Clone, Debug, PartialEq)]
+pub struct DedupeAsset {
+    pub path: PathBuf,
+    pub model_name: Option<String>
+}
+
+impl Display for DedupeAsset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.model_name {
+            Some(name) => write!(f, "{} ({})", self.path.display(), name),
+            None => write!(f, "{}", self.path.display())
+        }
+    }
+}
+
+/// Which kind of file a scan reads. A converted output always matches the source it came from,
+/// so sources and outputs are never scanned together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupeScope {
+    /// .obj files
+    #[default]
+    Sources,
+    /// .mdl and .cmdl files
+    Outputs
+}
+
+/// Assets with matching geometry hashes
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateGroup {
+    pub geometry_hash: u64,
+    pub assets: Vec<DedupeAsset>
+}
+
+/// The outcome of scanning a directory tree. Files that couldn't be read (including converted
+/// files that are truncated or from other versions of this tool) are skipped and listed
+/// separately.
+#[derive(Clone, Debug, Default)]
+pub struct DedupeReport {
+    pub assets_scanned: usize,
+    pub duplicate_groups: Vec<DuplicateGroup>,
+    pub skipped_files: Vec<PathBuf>
+}
+
+/// Hash geometry in a way that ignores where it is positioned, the order of its vertices and
+/// faces, and which vertex each face starts at. Positions are snapped to a grid with the given
+/// spacing first, so geometry that differs by less than that will usually hash the same. Normals
+/// and texture coordinates are not considered.
+pub fn canonical_geometry_hash(triangles: &[Triangle], tolerance: f32) -> u64 {
+    let mut origin = [f32::MAX; 3];
+    for triangle in triangles.iter() {
+        for point in triangle.iter() {
+            for axis in 0..3 {
+                origin[axis] = origin[axis].min(point[axis]);
+            }
+        }
+    }
+
+    let snap = |point: &Vec3| -> [i64; 3] {
+        [
+            ((point[0] - origin[0]) / tolerance).round() as i64,
+            ((point[1] - origin[1]) / tolerance).round() as i64,
+            ((point[2] - origin[2]) / tolerance).round() as i64
+        ]
+    };
+    let mut canonical_triangles: Vec<[[i64; 3]; 3]> = triangles.iter()
+        .map(|triangle| [snap(&triangle[0]), snap(&triangle[1]), snap(&triangle[2])])
+        .filter(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2])
+        .map(|t| {
+            // Rotate so the smallest point is first, which keeps the winding order
+            let first = (0..3).min_by_key(|i| t[*i]).unwrap();
+            [t[first], t[(first + 1) % 3], t[(first + 2) % 3]]
+        })
+        .collect();
+    canonical_triangles.sort_unstable();
+
+    let mut hasher = DefaultHasher::new();
+    canonical_triangles.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn triangles_of_model(model: &Model) -> Vec<Triangle> {
+    triangles_of_indexed_vertices(&model.interleaved_vertices, &model.face_indices)
+}
+
+fn triangles_of_indexed_vertices<I: Copy + Into<u32>>(interleaved_vertices: &[Vertex], face_indices: &[I]) -> Vec<Triangle> {
+    face_indices.chunks(3)
+        .map(|face| [
+            interleaved_vertices[face[0].into() as usize].position,
+            interleaved_vertices[face[1].into() as usize].position,
+            interleaved_vertices[face[2].into() as usize].position
+        ])
+        .collect()
+}
+
+fn triangles_of_compact_model(model: &CompactModel) -> Vec<Triangle> {
+    model.submeshes.iter()
+        .flat_map(|submesh| submesh.face_indices.chunks(3).map(move |face| [
+            submesh.vertices[face[0] as usize].position,
+            submesh.vertices[face[1] as usize].position,
+            submesh.vertices[face[2] as usize].position
+        ]))
+        .collect()
+}
+
+/// Read the geometry of each model in a .obj file, or a .mdl or .cmdl file, depending on the
+/// scope (None for other files)
+fn read_assets(path: &PathBuf, scope: DedupeScope) -> Option<Result<Vec<AssetGeometry>, ConversionError>> {
+    let extension = path.extension()?.to_str()?;
+    let asset = DedupeAsset { path: path.into(), model_name: None };
+    let result = match (scope, extension) {
+        (DedupeScope::Sources, "obj") => {
+            let mut factory = ModelFactory::new(path.into(), ClassificationOverrides::default());
+            factory.extract_all_models_from_file(false).map(|()| {
+                let source_asset = |model_name: &String| DedupeAsset { path: path.into(), model_name: Some(model_name.clone()) };
+                factory.get_models().iter()
+                    .map(|model| (source_asset(model.get_name()), triangles_of_model(model)))
+                    .chain(factory.get_large_models().iter()
+                        .map(|model| (source_asset(&model.name), triangles_of_indexed_vertices(&model.interleaved_vertices, &model.face_indices))))
+                    .collect()
+            })
+        },
+        (DedupeScope::Outputs, "mdl") | (DedupeScope::Outputs, "cmdl") => {
+            fs::read(path)
+                .map_err(|e| ConversionError::Io(path.into(), e))
+                .and_then(|bytes| match extension {
+                    "mdl" => Model::try_from_bytes(&bytes).map(|model| triangles_of_model(&model)),
+                    _ => CompactModel::try_from_bytes(&bytes).map(|model| triangles_of_compact_model(&model))
+                })
+                .map(|triangles| vec![(asset, triangles)])
+        },
+        _ => return None
+    };
+    Some(result)
+}
+
+fn collect_files(directory: &PathBuf, files: &mut Vec<PathBuf>) -> Result<(), ConversionError> {
+    let entries = fs::read_dir(directory)
+        .map_err(|e| ConversionError::Io(directory.into(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| ConversionError::Io(directory.into(), e))?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Scan a directory tree for source or converted models (as chosen by the scope), grouping those
+/// whose canonical geometry hashes match
+pub fn find_duplicates(directory: &PathBuf, tolerance: f32, scope: DedupeScope) -> Result<DedupeReport, ConversionError> {
+    let mut files: Vec<PathBuf> = vec![];
+    collect_files(directory, &mut files)?;
+    files.sort();
+
+    let mut report = DedupeReport::default();
+    let mut groups: HashMap<u64, Vec<DedupeAsset>> = HashMap::new();
+    for path in files.iter() {
+        match read_assets(path, scope) {
+            Some(Ok(assets)) => {
+                for (asset, triangles) in assets {
+                    report.assets_scanned += 1;
+                    if triangles.is_empty() {
+                        continue;
+                    }
+                    groups.entry(canonical_geometry_hash(&triangles, tolerance))
+                        .or_default()
+                        .push(asset);
+                }
+            },
+            Some(Err(_)) => report.skipped_files.push(path.into()),
+            None => ()
+        }
+    }
+
+    report.duplicate_groups = groups.into_iter()
+        .filter(|(_, assets)| assets.len() > 1)
+        .map(|(geometry_hash, assets)| DuplicateGroup { geometry_hash, assets })
+        .collect();
+    report.duplicate_groups.sort_by(|a, b| a.assets[0].path.cmp(&b.assets[0].path));
+    Ok(report)
+}
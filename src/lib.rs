@@ -1,19 +1,35 @@
 pub mod collisiondata;
+pub mod compactmodel;
+pub mod config;
+pub mod conversion;
+pub mod dedupe;
 pub mod model;
 pub mod modelfactory;
+pub mod samples;
+pub mod selftest;
 
 use std::fs;
 use std::path::PathBuf;
-use modelfactory::ModelFactory;
+use config::WcConfig;
+use conversion::{ConversionOptions, ConversionError};
+
+pub use conversion::convert_paths;
 
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
-    use crate::process_directory;
+    use crate::{process_directory, convert_paths};
+    use crate::conversion::{ConversionOptions, ConversionError, StagedFiles};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::compactmodel::{CompactModel, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES};
+    use crate::dedupe::{find_duplicates, DedupeScope, DEFAULT_DEDUPE_TOLERANCE};
     use crate::model::{Model, Vertex};
-    use crate::collisiondata::CollisionData;
+    use crate::collisiondata::{CollisionData, Classification, Vec3, Wall};
+    use crate::config::{ClassificationOverrides, WcConfig};
+    use crate::modelfactory::ModelFactory;
     use std::fs::File;
-    use std::io::Read;
+    use std::io::{Read, Write};
 
     fn expected_vertex_data() -> Vec<Vertex> {
         vec![
@@ -160,6 +176,7 @@ mod tests {
         let mut bytes = vec![0u8; size_bytes];
         file.read_exact(bytes.as_mut_slice()).unwrap();
         let collision_data = unsafe { CollisionData::from_bytes(bytes.as_slice()) };
+        assert!(CollisionData::try_from_bytes(&bytes[..(bytes.len() - 4)]).is_err());
 
         assert_eq!(collision_data.extent_x, [-3.0, 5.25]);
         assert_eq!(collision_data.extent_y, [0.0, 4.0]);
@@ -167,11 +184,814 @@ mod tests {
         assert_eq!(collision_data.traction_surfaces.len(), 18);
         assert_eq!(collision_data.sliding_surfaces.len(), 2);
         assert_eq!(collision_data.walls.len(), 18);
+        assert_eq!(collision_data.climbable_surfaces.len(), 0);
+        assert_eq!(collision_data.custom_surfaces.len(), 0);
+
+        // Walls should face the same way as their source faces, e.g. the inside of the wall at
+        // x = -3 faces into the enclosure. Source faces aren't kept in collision files, so check
+        // the faces' winding order against freshly extracted data.
+        let mut source_path = model_directory.clone();
+        source_path.push("Enclosure.obj");
+        let mut factory = ModelFactory::new(source_path, ClassificationOverrides::default());
+        factory.extract_all_models_from_file(true).unwrap();
+        assert_eq!(factory.get_collision_data()[0].walls.len(), 18);
+        assert!(factory.get_collision_data()[0].find_misoriented_walls().is_empty());
+        let west_wall = collision_data.walls.iter()
+            .find(|wall| wall.get_bottom_left().x == -3.0 && wall.get_top_right().x == -3.0)
+            .unwrap();
+        assert_eq!(*west_wall.get_normal(), Vec3 { x: 1.0, y: 0.0, z: 0.0 });
+    }
+
+    #[test]
+    fn flag_walls_against_winding_order() {
+        // The quad's stored normal points away from the side its winding order implies, so its
+        // wall follows the stored normal but is flagged. The triangle's normal is consistent.
+
+        let source = "o Walls
+v -1.0 0.0 0.0
+v 1.0 0.0 0.0
+v 1.0 2.0 0.0
+v -1.0 2.0 0.0
+v 5.0 0.0 0.0
+v 6.0 0.0 0.0
+v 6.0 1.0 0.0
+vt 0.0 0.0
+vn 0.0 0.0 -1.0
+vn 0.0 0.0 1.0
+f 1/1/1 2/1/1 3/1/1 4/1/1
+f 5/1/2 6/1/2 7/1/2
+";
+        let mut factory = ModelFactory::new(PathBuf::from("Walls.obj"), ClassificationOverrides::default());
+        factory.extract_all_models_from_str(source, true).unwrap();
+        let collision_data = &factory.get_collision_data()[0];
+        assert_eq!(collision_data.walls.len(), 2);
+        assert_eq!(*collision_data.walls[0].get_normal(), Vec3 { x: 0.0, y: 0.0, z: -1.0 });
+        assert_eq!(*collision_data.walls[1].get_normal(), Vec3 { x: 0.0, y: 0.0, z: 1.0 });
+        assert_eq!(collision_data.find_misoriented_walls(), vec![0]);
+
+        // Editing the walls directly doesn't pair the remaining walls with the wrong faces
+        let mut edited_collision_data = CollisionData::new(String::from("Walls"));
+        for wall in collision_data.walls.iter() {
+            edited_collision_data.add_wall(*wall, *wall.get_normal() * -1.0);
+        }
+        edited_collision_data.walls.remove(0);
+        assert_eq!(edited_collision_data.find_misoriented_walls(), vec![0]);
+        edited_collision_data.walls.insert(0, collision_data.walls[1]);
+        assert_eq!(edited_collision_data.find_misoriented_walls(), vec![0, 1]);
+        edited_collision_data.walls.clear();
+        edited_collision_data.walls.push(Wall::from_bottom_left_to_top_right(Vec3 { x: 0.0, y: 0.0, z: 0.0 }, Vec3 { x: 1.0, y: 1.0, z: 0.0 }));
+        assert!(edited_collision_data.find_misoriented_walls().is_empty());
+
+        // The misoriented wall is reported once for the model rather than once per face
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("winding");
+        std::fs::create_dir_all(&output_directory).unwrap();
+        let output = factory.export_all(&output_directory, Some(&output_directory), false).unwrap();
+        assert_eq!(output.files_written.len(), 2);
+        assert_eq!(output.warnings, vec!["1 wall(s) in Walls have normals disagreeing with the winding order of their faces"]);
+    }
+
+    #[test]
+    fn convert_list_of_paths_in_parallel() {
+        // Converts an arbitrary list of files from different directories, one of which doesn't
+        // exist, and checks that each gets its own result in the order given
+
+        let mut resources_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        resources_directory.push("resources");
+        let mut output_directory = resources_directory.clone();
+        output_directory.push("models");
+        output_directory.push("paths");
+        if !output_directory.is_dir() {
+            std::fs::create_dir_all(&output_directory).unwrap();
+        }
+        let source_paths: Vec<PathBuf> = ["scrutiny/Cube.obj", "closed/Enclosure.obj", "closed/Missing.obj"].iter()
+            .map(|name| {
+                let mut path = resources_directory.clone();
+                path.push("tests");
+                path.push(name);
+                path
+            })
+            .collect();
+
+        let mut options = ConversionOptions::new(output_directory.clone());
+        options.collisions_dst_path = Some(output_directory.clone());
+        options.parallel = true;
+        let results = convert_paths(source_paths.clone(), &options);
+
+        assert_eq!(results.len(), 3);
+        for (result, source_path) in results.iter().zip(source_paths.iter()) {
+            assert_eq!(&result.source_path, source_path);
+        }
+        let file_names = |index: usize| -> Vec<String> {
+            results[index].result.as_ref().unwrap().files_written.iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(file_names(0), vec!["Cube.mdl", "Cube.csn"]);
+        assert_eq!(file_names(1), vec!["Enclosure.mdl", "Enclosure.csn"]);
+        assert!(matches!(results[2].result, Err(ConversionError::Io(_, _))));
+    }
+
+    #[test]
+    fn cancel_conversion() {
+        // Converting with a token that is already set should produce nothing, and staged files
+        // that are never committed should be cleaned up
+
+        let mut source_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_path.push("resources");
+        source_path.push("tests");
+        source_path.push("closed");
+        source_path.push("Enclosure.obj");
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("cancelled");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        std::fs::create_dir_all(&output_directory).unwrap();
+
+        let mut options = ConversionOptions::new(output_directory.clone());
+        options.collisions_dst_path = Some(output_directory.clone());
+        options.cancellation_token = Some(Arc::new(AtomicBool::new(true)));
+        let results = convert_paths(vec![source_path.clone(), source_path], &options);
+        assert_eq!(results.len(), 2);
+        for result in results.iter() {
+            assert!(matches!(result.result, Err(ConversionError::Cancelled)));
+        }
+
+        let mut abandoned_file_path = output_directory.clone();
+        abandoned_file_path.push("Abandoned.mdl");
+        {
+            let mut staged_files = StagedFiles::new();
+            staged_files.create(&abandoned_file_path).unwrap();
+        }
+        assert_eq!(std::fs::read_dir(&output_directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn cancel_conversion_partway() {
+        // Cancelling between files should keep the outputs of the file already converted, and
+        // leave nothing for the next. Cancelling between extraction and export should leave
+        // nothing at all.
+
+        let mut source_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_directory.push("resources");
+        source_directory.push("tests");
+        let mut cube_path = source_directory.clone();
+        cube_path.push("scrutiny");
+        cube_path.push("Cube.obj");
+        let mut enclosure_path = source_directory.clone();
+        enclosure_path.push("closed");
+        enclosure_path.push("Enclosure.obj");
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("cancelledpartway");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        std::fs::create_dir_all(&output_directory).unwrap();
+        let output_file_names = || -> Vec<String> {
+            let mut names: Vec<String> = std::fs::read_dir(&output_directory).unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let token = Arc::new(AtomicBool::new(false));
+        let mut options = ConversionOptions::new(output_directory.clone());
+        options.collisions_dst_path = Some(output_directory.clone());
+        options.cancellation_token = Some(token.clone());
+        let paths = vec![cube_path, enclosure_path.clone()].into_iter()
+            .enumerate()
+            .map(|(index, path)| {
+                if index == 1 {
+                    token.store(true, Ordering::Relaxed);
+                }
+                path
+            });
+        let results = convert_paths(paths, &options);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1].result, Err(ConversionError::Cancelled)));
+        assert_eq!(output_file_names(), vec!["Cube.csn", "Cube.mdl"]);
+
+        std::fs::remove_dir_all(&output_directory).unwrap();
+        std::fs::create_dir_all(&output_directory).unwrap();
+        let token = Arc::new(AtomicBool::new(false));
+        let mut factory = ModelFactory::new(enclosure_path, ClassificationOverrides::default());
+        factory.set_cancellation_token(token.clone());
+        factory.extract_all_models_from_file(true).unwrap();
+        token.store(true, Ordering::Relaxed);
+        let result = factory.export_all(&output_directory, Some(&output_directory), false);
+        assert!(matches!(result, Err(ConversionError::Cancelled)));
+        assert!(output_file_names().is_empty());
+    }
+
+    #[test]
+    fn clean_up_after_failed_commit() {
+        // The second file can't be moved into place because a directory is in the way. The first
+        // has already been moved, and the third should still be cleaned up.
+
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("failedcommit");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        let file_paths: Vec<PathBuf> = ["First.mdl", "Second.mdl", "Third.mdl"].iter()
+            .map(|name| {
+                let mut path = output_directory.clone();
+                path.push(name);
+                path
+            })
+            .collect();
+        std::fs::create_dir_all(&file_paths[1]).unwrap();
+
+        let mut staged_files = StagedFiles::new();
+        for path in file_paths.iter() {
+            staged_files.create(path).unwrap();
+        }
+        match staged_files.commit() {
+            Err(ConversionError::Io(path, _)) => assert_eq!(path, file_paths[1]),
+            other => panic!("Expected an I/O error, got {:?}", other)
+        }
+        assert!(file_paths[0].is_file());
+        assert!(file_paths[1].is_dir());
+        assert!(!file_paths[2].exists());
+        assert_eq!(std::fs::read_dir(&output_directory).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn stage_same_output_twice() {
+        // Conversions running in parallel may write outputs with the same name, and shouldn't
+        // write to each other's temporary files
+
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("samename");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        std::fs::create_dir_all(&output_directory).unwrap();
+        let mut file_path = output_directory.clone();
+        file_path.push("Shared.mdl");
+
+        let mut first_staged_files = StagedFiles::new();
+        let mut second_staged_files = StagedFiles::new();
+        first_staged_files.create(&file_path).unwrap().write_all(b"first").unwrap();
+        second_staged_files.create(&file_path).unwrap().write_all(b"second").unwrap();
+        assert_eq!(std::fs::read_dir(&output_directory).unwrap().count(), 2);
+
+        first_staged_files.commit().unwrap();
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"first");
+        second_staged_files.commit().unwrap();
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(&output_directory).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn convert_cube_for_mobile() {
+        // Transcodes the Cube model with the mobile profile, checks the reported size reduction,
+        // then decodes the compact file and checks it has the same triangles as the source (faces
+        // and their starting vertices may be reordered, but winding is preserved)
+
+        let mut source_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_path.push("resources");
+        source_path.push("tests");
+        source_path.push("scrutiny");
+        source_path.push("Cube.obj");
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("mobile");
+        if !output_directory.is_dir() {
+            std::fs::create_dir_all(&output_directory).unwrap();
+        }
+        let mut options = ConversionOptions::new(output_directory.clone());
+        options.mobile = true;
+        let results = convert_paths(vec![source_path], &options);
+        let output = results[0].result.as_ref().unwrap();
+        assert_eq!(output.size_reports.len(), 1);
+        assert_eq!(output.size_reports[0].standard_bytes, 12 + 24 * 32 + 36 * 2);
+        assert!(output.size_reports[0].optimized_bytes < output.size_reports[0].standard_bytes);
+
+        let mut model_file_path = output_directory;
+        model_file_path.push("Cube.cmdl");
+        assert_eq!(output.files_written, vec![model_file_path.clone()]);
+        let bytes = std::fs::read(&model_file_path).unwrap();
+        assert_eq!(bytes.len(), output.size_reports[0].optimized_bytes);
+        let compact_model = CompactModel::from_bytes(bytes.as_slice());
+        assert_eq!(compact_model.submeshes.len(), 1);
+        let submesh = &compact_model.submeshes[0];
+        assert_eq!(submesh.vertices.len(), 24);
+        assert_eq!(submesh.face_indices.len(), 36);
+
+        let expected_vertices = expected_vertex_data();
+        let expected_triangles: Vec<Vec<Vertex>> = expected_index_data().chunks(3)
+            .map(|face| face.iter().map(|index| expected_vertices[*index as usize]).collect())
+            .collect();
+        for face in submesh.face_indices.chunks(3) {
+            let triangle: Vec<Vertex> = face.iter()
+                .map(|index| submesh.vertices[*index as usize].to_vertex())
+                .collect();
+            let is_expected = (0..3).any(|rotation| {
+                let rotated: Vec<Vertex> = (0..3).map(|i| triangle[(i + rotation) % 3]).collect();
+                expected_triangles.contains(&rotated)
+            });
+            assert!(is_expected, "Unexpected triangle: {:?}", triangle);
+        }
+
+        // Limiting vertices per submesh should split the cube without losing any faces
+        let mut cube = Model::new(String::from("Cube"));
+        cube.interleaved_vertices = expected_vertices;
+        cube.face_indices = expected_index_data();
+        let split_model = CompactModel::from_model(&cube, MOBILE_WELD_TOLERANCE, 8);
+        assert_eq!(split_model.submeshes.len(), 3);
+        for submesh in split_model.submeshes.iter() {
+            assert!(submesh.vertices.len() <= 8);
+        }
+        let face_count: usize = split_model.submeshes.iter().map(|submesh| submesh.face_indices.len() / 3).sum();
+        assert_eq!(face_count, 12);
+    }
+
+    #[test]
+    fn generate_sample_corpus() {
+        // Writes the samples, which are converted as part of generation, then reads back the
+        // expected outputs to check n-gons, missing texture coordinates, negative indices and
+        // material overrides were all handled
+
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("samples");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        let files_written = crate::samples::generate_samples(&output_directory, false).unwrap();
+        assert_eq!(files_written.len(), 1 + 5 + 7 * 2 + 5);
+
+        // The directory now has the samples in it, so generating again needs to be forced
+        match crate::samples::generate_samples(&output_directory, false) {
+            Err(ConversionError::Io(path, e)) => {
+                assert_eq!(path, output_directory);
+                assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+            },
+            other => panic!("Expected generating into a non-empty directory to fail, got {:?}", other)
+        }
+        let files_written = crate::samples::generate_samples(&output_directory, true).unwrap();
+        assert_eq!(files_written.len(), 1 + 5 + 7 * 2 + 5);
+
+        let mut expected_directory = output_directory.clone();
+        expected_directory.push(crate::samples::EXPECTED_DIRECTORY_NAME);
+        let read_model = |name: &str| -> Model {
+            let mut path = expected_directory.clone();
+            path.push(name);
+            unsafe { Model::from_bytes(&std::fs::read(path).unwrap()) }
+        };
+        let read_collisions = |name: &str| -> CollisionData {
+            let mut path = expected_directory.clone();
+            path.push(name);
+            unsafe { CollisionData::from_bytes(&std::fs::read(path).unwrap()) }
+        };
+
+        let hexagon = read_model("Hexagon.mdl");
+        assert_eq!(hexagon.interleaved_vertices.len(), 6);
+        assert_eq!(hexagon.face_indices, vec![0, 1, 2, 0, 2, 3, 0, 3, 4, 0, 4, 5]);
+        assert_eq!(read_collisions("Hexagon.csn").traction_surfaces.len(), 4);
+
+        assert_eq!(read_collisions("Floor.csn").traction_surfaces.len(), 2);
+        assert_eq!(read_collisions("Wall.csn").walls.len(), 1);
+        assert_eq!(read_collisions("Ramp.csn").sliding_surfaces.len(), 2);
+
+        let plain = read_model("Plain.mdl");
+        assert_eq!(plain.interleaved_vertices.len(), 4);
+        assert!(plain.interleaved_vertices.iter().all(|vertex| vertex.tex_coord == [0.0, 0.0]));
+
+        let relative = read_model("Relative.mdl");
+        assert_eq!(relative.interleaved_vertices.len(), 8);
+        assert_eq!(relative.face_indices.len(), 12);
+        assert_eq!(relative.interleaved_vertices[4].position, [2.0, 0.0, 0.0]);
+        assert_eq!(relative.interleaved_vertices[7].tex_coord, [0.0, 1.0]);
+
+        let tower = read_collisions("Tower.csn");
+        assert_eq!(tower.traction_surfaces.len(), 2);
+        assert_eq!(tower.climbable_surfaces.len(), 2);
+        assert_eq!(tower.sliding_surfaces.len(), 2);
+        assert_eq!(tower.walls.len(), 0);
+
+        let mut summary_path = expected_directory.clone();
+        summary_path.push("materials.txt");
+        let summary = std::fs::read_to_string(summary_path).unwrap();
+        assert!(summary.contains("Tower.csn: 2 traction surfaces, 2 sliding surfaces, 0 walls, 2 climbable surfaces, 0 custom surfaces"));
+    }
+
+    #[test]
+    fn parse_optional_tex_coords_and_negative_indices() {
+        // Vertices written as position//normal get zero texture coordinates, and stay separate
+        // from vertices with the same position and normal that do have texture coordinates (even
+        // for the largest index that fits). Negative indices count back from the last element read.
+
+        let mut source = String::from("o Mixed\nv 0.0 0.0 0.0\nv 1.0 0.0 0.0\nv 0.0 0.0 -1.0\nvn 0.0 1.0 0.0\nvt 0.25 0.75\n");
+        for _ in 1..65536 {
+            source.push_str("vt 0.5 0.5\n");
+        }
+        source.push_str("f 1//1 2//1 3//1\nf 1/65536/1 2/65536/1 3/65536/1\n");
+        source.push_str("o Relative\nv 2.0 0.0 0.0\nv 3.0 0.0 0.0\nv 2.0 0.0 -1.0\nf -3/-65536/-1 -2/-65536/-1 -1/-65536/-1\n");
+        let mut factory = ModelFactory::new(PathBuf::from("Mixed.obj"), ClassificationOverrides::default());
+        factory.extract_all_models_from_str(&source, true).unwrap();
+
+        let mixed = &factory.get_models()[0];
+        assert_eq!(mixed.interleaved_vertices.len(), 6);
+        assert_eq!(mixed.face_indices, vec![0, 1, 2, 3, 4, 5]);
+        assert!(mixed.interleaved_vertices[0..3].iter().all(|vertex| vertex.tex_coord == [0.0, 0.0]));
+        assert!(mixed.interleaved_vertices[3..6].iter().all(|vertex| vertex.tex_coord == [0.5, 0.5]));
+
+        let relative = &factory.get_models()[1];
+        assert_eq!(relative.interleaved_vertices.len(), 3);
+        assert_eq!(relative.interleaved_vertices[0].position, [2.0, 0.0, 0.0]);
+        assert_eq!(relative.interleaved_vertices[2].position, [2.0, 0.0, -1.0]);
+        assert!(relative.interleaved_vertices.iter().all(|vertex| vertex.tex_coord == [0.25, 0.75]));
+        assert_eq!(factory.get_collision_data()[1].traction_surfaces.len(), 1);
+
+        for bad_face in ["f 1/1 2/1 3/1", "f 0//1 1//1 2//1", "f -4//1 -2//1 -1//1"].iter() {
+            let source = format!("o Broken\nv 0.0 0.0 0.0\nv 1.0 0.0 0.0\nv 0.0 0.0 -1.0\nvt 0.0 0.0\nvn 0.0 1.0 0.0\n{}\n", bad_face);
+            let mut factory = ModelFactory::new(PathBuf::from("Broken.obj"), ClassificationOverrides::default());
+            match factory.extract_all_models_from_str(&source, false) {
+                Err(ConversionError::Parse(_)) => (),
+                other => panic!("{} should fail to parse, got {:?}", bad_face, other.map(|_| ()))
+            }
+        }
+    }
+
+    #[test]
+    fn convert_large_model_for_mobile() {
+        // A model with more vertices than 16-bit indices can address is split into submeshes by
+        // the mobile profile, with every triangle intact, and is refused for a standard .mdl file
+
+        let mut source_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_directory.push("resources");
+        source_directory.push("models");
+        source_directory.push("large");
+        let mut output_directory = source_directory.clone();
+        output_directory.push("output");
+        std::fs::create_dir_all(&output_directory).unwrap();
+
+        let triangle_count = 23334;
+        let mut source = String::from("o Large\nvt 0.0 0.0\nvn 0.0 1.0 0.0\n");
+        let mut expected_triangles: Vec<[[u32; 3]; 3]> = vec![];
+        for i in 0..triangle_count {
+            let x = (i % 200) as f32 * 2.0;
+            let z = -((i / 200) as f32) * 2.0;
+            let points = [[x, 0.0, z], [x + 1.0, 0.0, z], [x, 0.0, z - 1.0]];
+            for point in points.iter() {
+                source.push_str(&format!("v {} {} {}\n", point[0], point[1], point[2]));
+            }
+            source.push_str(&format!("f {}/1/1 {}/1/1 {}/1/1\n", i * 3 + 1, i * 3 + 2, i * 3 + 3));
+            expected_triangles.push([
+                [points[0][0].to_bits(), points[0][1].to_bits(), points[0][2].to_bits()],
+                [points[1][0].to_bits(), points[1][1].to_bits(), points[1][2].to_bits()],
+                [points[2][0].to_bits(), points[2][1].to_bits(), points[2][2].to_bits()]
+            ]);
+        }
+        let mut source_path = source_directory.clone();
+        source_path.push("Large.obj");
+        std::fs::write(&source_path, source).unwrap();
+
+        let mut options = ConversionOptions::new(output_directory.clone());
+        let results = convert_paths(vec![source_path.clone()], &options);
+        match &results[0].result {
+            Err(ConversionError::Encoding(_)) => (),
+            other => panic!("Expected an encoding error, got {:?}", other)
+        }
+
+        options.mobile = true;
+        let results = convert_paths(vec![source_path], &options);
+        let output = results[0].result.as_ref().unwrap();
+        let bytes = std::fs::read(&output.files_written[0]).unwrap();
+        let compact_model = CompactModel::from_bytes(bytes.as_slice());
+        assert_eq!(compact_model.submeshes.len(), 2);
+        let vertex_count: usize = compact_model.submeshes.iter().map(|submesh| submesh.vertices.len()).sum();
+        assert_eq!(vertex_count, triangle_count * 3);
+
+        let mut triangles: Vec<[[u32; 3]; 3]> = vec![];
+        for submesh in compact_model.submeshes.iter() {
+            assert!(submesh.vertices.len() <= 65535);
+            for face in submesh.face_indices.chunks(3) {
+                let first = (0..3).min_by_key(|i| face[*i]).unwrap();
+                let points: Vec<[u32; 3]> = (0..3)
+                    .map(|i| submesh.vertices[face[(first + i) % 3] as usize].position)
+                    .map(|position| [position[0].to_bits(), position[1].to_bits(), position[2].to_bits()])
+                    .collect();
+                triangles.push([points[0], points[1], points[2]]);
+            }
+        }
+        // Each triangle's vertices were added in corner order, so putting the lowest index first
+        // restores the source corner order
+        triangles.sort_unstable();
+        expected_triangles.sort_unstable();
+        assert_eq!(triangles, expected_triangles);
+    }
+
+    #[test]
+    fn self_test_passes() {
+        let results = crate::selftest::run_self_test();
+        assert_eq!(results.len(), 8);
+        for result in results.iter() {
+            assert!(result.passed(), "{}: {:?}", result.name, result.failures);
+        }
+    }
+
+    #[test]
+    fn report_duplicate_sources() {
+        // Scans the sources in resources/tests/dedupe. The Ramps model is the Enclosure model with
+        // extra material assignments, and MovedCube is the Cube model translated, so both pairs
+        // should be found.
+
+        let mut source_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_directory.push("resources");
+        source_directory.push("tests");
+        source_directory.push("dedupe");
+        let report = find_duplicates(&source_directory, DEFAULT_DEDUPE_TOLERANCE, DedupeScope::Sources).unwrap();
+
+        assert_eq!(report.assets_scanned, 4);
+        assert!(report.skipped_files.is_empty());
+        let group_names: Vec<Vec<String>> = report.duplicate_groups.iter()
+            .map(|group| group.assets.iter().map(|asset| asset.model_name.clone().unwrap()).collect())
+            .collect();
+        assert_eq!(group_names, vec![
+            vec![String::from("Cube"), String::from("MovedCube")],
+            vec![String::from("Enclosure"), String::from("Ramps")]
+        ]);
+    }
+
+    #[test]
+    fn scan_sources_apart_from_their_outputs() {
+        // A converted model sits next to its source. Neither scope sees both, so the pair isn't
+        // reported as a duplicate.
+
+        let mut source_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_path.push("resources");
+        source_path.push("tests");
+        source_path.push("scrutiny");
+        source_path.push("Cube.obj");
+        let mut asset_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        asset_directory.push("resources");
+        asset_directory.push("models");
+        asset_directory.push("mixed");
+        if asset_directory.is_dir() {
+            std::fs::remove_dir_all(&asset_directory).unwrap();
+        }
+        std::fs::create_dir_all(&asset_directory).unwrap();
+        let mut copied_source_path = asset_directory.clone();
+        copied_source_path.push("Cube.obj");
+        std::fs::copy(&source_path, &copied_source_path).unwrap();
+        let results = convert_paths(vec![copied_source_path.clone()], &ConversionOptions::new(asset_directory.clone()));
+        let output_path = results[0].result.as_ref().unwrap().files_written[0].clone();
+
+        let source_report = find_duplicates(&asset_directory, DEFAULT_DEDUPE_TOLERANCE, DedupeScope::Sources).unwrap();
+        assert_eq!(source_report.assets_scanned, 1);
+        assert!(source_report.duplicate_groups.is_empty());
+
+        let output_report = find_duplicates(&asset_directory, DEFAULT_DEDUPE_TOLERANCE, DedupeScope::Outputs).unwrap();
+        assert_eq!(output_report.assets_scanned, 1);
+        assert!(output_report.duplicate_groups.is_empty());
+
+        // A copy of the output is a genuine duplicate
+        let mut copied_output_path = asset_directory.clone();
+        copied_output_path.push("CubeCopy.mdl");
+        std::fs::copy(&output_path, &copied_output_path).unwrap();
+        let output_report = find_duplicates(&asset_directory, DEFAULT_DEDUPE_TOLERANCE, DedupeScope::Outputs).unwrap();
+        assert_eq!(output_report.assets_scanned, 2);
+        assert_eq!(output_report.duplicate_groups.len(), 1);
+        assert_eq!(output_report.duplicate_groups[0].assets.len(), 2);
+    }
+
+    #[test]
+    fn skip_truncated_converted_models() {
+        // Converted files cut short (with an intact version header) can't be decoded, so they are
+        // listed as skipped rather than stopping the scan
+
+        let mut source_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_path.push("resources");
+        source_path.push("tests");
+        source_path.push("scrutiny");
+        source_path.push("Cube.obj");
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("truncated");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        std::fs::create_dir_all(&output_directory).unwrap();
+
+        let mut factory = ModelFactory::new(source_path, ClassificationOverrides::default());
+        factory.extract_all_models_from_file(false).unwrap();
+        let model = &factory.get_models()[0];
+        let mut model_bytes: Vec<u8> = vec![];
+        unsafe { model.write_data_to_file(&mut model_bytes).unwrap(); }
+        let compact_bytes = CompactModel::from_model(model, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES).to_bytes().unwrap();
+        assert!(Model::try_from_bytes(&model_bytes).is_ok());
+        assert!(Model::try_from_bytes(&model_bytes[..(model_bytes.len() - 2)]).is_err());
+        assert!(CompactModel::try_from_bytes(&compact_bytes).is_ok());
+        assert!(CompactModel::try_from_bytes(&compact_bytes[..(compact_bytes.len() / 2)]).is_err());
+
+        let write_output = |file_name: &str, bytes: &[u8]| -> PathBuf {
+            let mut path = output_directory.clone();
+            path.push(file_name);
+            std::fs::write(&path, bytes).unwrap();
+            path
+        };
+        write_output("Whole.mdl", &model_bytes);
+        let truncated_model_path = write_output("Truncated.mdl", &model_bytes[..(model_bytes.len() / 2)]);
+        let truncated_compact_path = write_output("Truncated.cmdl", &compact_bytes[..(compact_bytes.len() / 2)]);
+        let report = find_duplicates(&output_directory, DEFAULT_DEDUPE_TOLERANCE, DedupeScope::Outputs).unwrap();
+
+        assert_eq!(report.assets_scanned, 1);
+        assert!(report.duplicate_groups.is_empty());
+        assert_eq!(report.skipped_files, vec![truncated_compact_path, truncated_model_path]);
+    }
+
+    #[test]
+    fn suppress_ignored_objects_and_files() {
+        // The ignored directory's wc.toml ignores objects starting with _ref, and its .wcignore
+        // ignores blockout files, so only the Crate object from Scene.obj should be exported
+
+        let mut source_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        source_directory.push("resources");
+        source_directory.push("tests");
+        source_directory.push("ignored");
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        output_directory.push("ignored");
+        if output_directory.is_dir() {
+            std::fs::remove_dir_all(&output_directory).unwrap();
+        }
+        std::fs::create_dir_all(&output_directory).unwrap();
+        let source_paths: Vec<PathBuf> = ["Scene.obj", "blockout_Wall.obj"].iter()
+            .map(|name| {
+                let mut path = source_directory.clone();
+                path.push(name);
+                path
+            })
+            .collect();
+
+        let mut options = ConversionOptions::new(output_directory.clone());
+        options.collisions_dst_path = Some(output_directory.clone());
+        let results = convert_paths(source_paths, &options);
+
+        let scene_output = results[0].result.as_ref().unwrap();
+        let file_names: Vec<String> = scene_output.files_written.iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(file_names, vec!["Crate.mdl", "Crate.csn"]);
+        assert_eq!(scene_output.ignored_models, vec!["_refHuman"]);
+
+        let blockout_output = results[1].result.as_ref().unwrap();
+        assert!(blockout_output.files_written.is_empty());
+        assert_eq!(blockout_output.ignored_models, vec!["Wall"]);
+
+        assert_eq!(std::fs::read_dir(&output_directory).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn name_config_file_in_errors() {
+        // Problems in wc.toml and .wcignore should be reported against the file they came from
+
+        let mut base_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        base_directory.push("resources");
+        base_directory.push("models");
+        base_directory.push("badconfig");
+        let mut config_directory = base_directory.clone();
+        config_directory.push("config");
+        let mut ignore_directory = base_directory.clone();
+        ignore_directory.push("ignore");
+        std::fs::create_dir_all(&config_directory).unwrap();
+        std::fs::create_dir_all(&ignore_directory).unwrap();
+
+        let mut config_path = config_directory.clone();
+        config_path.push("wc.toml");
+        std::fs::write(&config_path, "[classification.materials]\nLadder = \"ladder\"\n").unwrap();
+        match WcConfig::try_load_from_directory(&config_directory) {
+            Err(ConversionError::Config(path, message)) => {
+                assert_eq!(path, config_path);
+                assert!(message.contains("ladder"));
+            },
+            other => panic!("Expected a config error, got {:?}", other)
+        }
+
+        let mut ignore_path = ignore_directory.clone();
+        ignore_path.push(".wcignore");
+        std::fs::write(&ignore_path, "# Reference geometry\n_ref*\n[unclosed\n").unwrap();
+        match WcConfig::try_load_from_directory(&ignore_directory) {
+            Err(ConversionError::Config(path, message)) => {
+                assert_eq!(path, ignore_path);
+                assert!(message.contains("line 3"));
+            },
+            other => panic!("Expected a config error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reject_invalid_categories() {
+        // Category IDs too large to be given a classification code, and categories named after
+        // built-in classifications, are errors even if nothing uses them
+
+        let resolve = |config: &str| -> Result<ClassificationOverrides, ConversionError> {
+            let config: WcConfig = toml::from_str(config).unwrap();
+            config.classification_overrides()
+        };
+        assert!(resolve("[classification.categories]\nladder = 4294967039\n").is_ok());
+        match resolve("[classification.categories]\nladder = 4294967040\n\n[classification.materials]\nLadder = \"ladder\"\n") {
+            Err(ConversionError::Config(_, message)) => assert!(message.contains("ladder")),
+            other => panic!("Expected a config error, got {:?}", other)
+        }
+        match resolve("[classification.categories]\nwall = 5\n") {
+            Err(ConversionError::Config(_, message)) => assert!(message.contains("built-in")),
+            other => panic!("Expected a config error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reject_inverted_climbable_band() {
+        // A climbable band can't have its minimum elevation above its maximum
+
+        let resolve = |config: &str| -> Result<ClassificationOverrides, ConversionError> {
+            let config: WcConfig = toml::from_str(config).unwrap();
+            config.classification_overrides()
+        };
+        assert!(resolve("[classification.climbable]\nmin_elevation = 20.0\nmax_elevation = 20.0\n").is_ok());
+        match resolve("[classification.climbable]\nmin_elevation = 30.0\nmax_elevation = 20.0\n") {
+            Err(ConversionError::Config(_, message)) => assert!(message.contains("min_elevation")),
+            other => panic!("Expected a config error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn apply_classification_overrides() {
+        // Transcodes the Ramps model (the Enclosure model with some faces given their own
+        // materials) using the overrides in its wc.toml, which force the sloped ramp to traction
+        // and the ledge wall into a custom category, and mark the 45-degree slope as climbable
+
+        let mut model_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        model_directory.push("resources");
+        model_directory.push("tests");
+        model_directory.push("overrides");
+        let mut output_directory = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        output_directory.push("resources");
+        output_directory.push("models");
+        if !output_directory.is_dir() {
+            std::fs::create_dir(&output_directory).unwrap();
+        }
+        process_directory(&model_directory, &output_directory, Some(&output_directory));
+
+        let mut collision_file_path = output_directory;
+        collision_file_path.push("Ramps.csn");
+        assert!(collision_file_path.is_file());
+        let mut file = File::open(&collision_file_path).unwrap();
+        let metadata = std::fs::metadata(&collision_file_path).unwrap();
+        let size_bytes = metadata.len() as usize;
+        let mut bytes = vec![0u8; size_bytes];
+        file.read_exact(bytes.as_mut_slice()).unwrap();
+        let collision_data = unsafe { CollisionData::from_bytes(bytes.as_slice()) };
+
+        assert_eq!(collision_data.extent_x, [-3.0, 5.25]);
+        assert_eq!(collision_data.extent_y, [0.0, 4.0]);
+        assert_eq!(collision_data.extent_z, [-5.0, 3.0]);
+        assert_eq!(collision_data.traction_surfaces.len(), 18);
+        assert_eq!(collision_data.sliding_surfaces.len(), 0);
+        assert_eq!(collision_data.walls.len(), 17);
+        assert_eq!(collision_data.climbable_surfaces.len(), 2);
+        assert_eq!(collision_data.custom_surfaces.len(), 2);
+        for surface in collision_data.custom_surfaces.iter() {
+            assert_eq!(surface.classification(), Classification::Custom(3));
+        }
     }
 }
 
 pub fn process_directory(src_path: &PathBuf, dst_path: &PathBuf, collisions_dst_path: Option<&PathBuf>) {
+    let mut options = ConversionOptions::new(dst_path.into());
+    options.collisions_dst_path = collisions_dst_path.cloned();
+    process_directory_with_options(src_path, &options);
+}
+
+/// Convert all .obj files in a directory, printing the files written (panics on any error other
+/// than cancellation). If the options have no config, the directory's wc.toml is used.
+pub fn process_directory_with_options(src_path: &PathBuf, options: &ConversionOptions) {
     println!("Processing models in directory {:?}: ", src_path);
+    let mut options = options.clone();
+    if options.config.is_none() {
+        options.config = Some(WcConfig::load_from_directory(src_path));
+    }
+
+    let mut source_paths: Vec<PathBuf> = vec![];
     for entry in fs::read_dir(src_path).unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
@@ -180,16 +1000,34 @@ pub fn process_directory(src_path: &PathBuf, dst_path: &PathBuf, collisions_dst_
             None => continue
         };
         match extension.to_str() {
-            Some("obj") => process_file(path, dst_path, collisions_dst_path),
+            Some("obj") => source_paths.push(path),
             _ => continue
         };
     }
-    println!("Models successfully processed");
-}
 
-fn process_file(src_file_path: PathBuf, dst_path: &PathBuf, collisions_dst_path: Option<&PathBuf>) {
-    let mut factory = ModelFactory::new(src_file_path);
-    let include_collisions = collisions_dst_path.is_some();
-    factory.extract_all_models_from_file(include_collisions);
-    factory.export_all(dst_path, collisions_dst_path);
+    for file_result in convert_paths(source_paths, &options) {
+        match file_result.result {
+            Ok(output) => {
+                println!("Files written:");
+                for path in output.files_written.iter() {
+                    println!(" {}", path.file_name().unwrap().to_string_lossy());
+                }
+                for model_name in output.ignored_models.iter() {
+                    println!(" {} (ignored, not exported)", model_name);
+                }
+                for report in output.size_reports.iter() {
+                    println!(" {}: {} -> {} bytes ({:.1}% smaller)", report.model_name, report.standard_bytes, report.optimized_bytes, report.reduction_percent());
+                }
+                for warning in output.warnings.iter() {
+                    println!("Warning: {}", warning);
+                }
+            },
+            Err(ConversionError::Cancelled) => {
+                println!("Processing cancelled");
+                return;
+            },
+            Err(e) => panic!("Error processing {:?}: {}", file_result.source_path, e)
+        }
+    }
+    println!("Models successfully processed");
 }
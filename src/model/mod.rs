@@ -1,13 +1,16 @@
 use std::collections::HashMap;
 use std::io::Write;
-use std::fs::File;
 use std::fmt::{Debug, Formatter};
 
 use crate::modelfactory::FILE_VERSION_NUMBER;
+use crate::conversion::ConversionError;
 
 pub type Vec2 = [f32; 2];
 pub type Vec3 = [f32; 3];
 
+/// The most vertices a model can have and still be indexed with 16 bits, as .mdl files are
+pub const MAX_MDL_VERTICES: usize = 65536;
+
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct Vertex {
@@ -63,17 +66,29 @@ impl RawModelData {
         self.raw_tex_coords.push(tex_coord);
     }
 
-    pub fn get_raw_position(&self, index: u16) -> Option<&Vec3> {
+    pub fn get_raw_position(&self, index: u32) -> Option<&Vec3> {
         self.raw_positions.get(index as usize)
     }
 
-    pub fn get_raw_normal(&self, index: u16) -> Option<&Vec3> {
+    pub fn get_raw_normal(&self, index: u32) -> Option<&Vec3> {
         self.raw_normals.get(index as usize)
     }
 
-    pub fn get_raw_tex_coord(&self, index: u16) -> Option<&Vec2> {
+    pub fn get_raw_tex_coord(&self, index: u32) -> Option<&Vec2> {
         self.raw_tex_coords.get(index as usize)
     }
+
+    pub fn position_count(&self) -> usize {
+        self.raw_positions.len()
+    }
+
+    pub fn normal_count(&self) -> usize {
+        self.raw_normals.len()
+    }
+
+    pub fn tex_coord_count(&self) -> usize {
+        self.raw_tex_coords.len()
+    }
 }
 
 impl Default for RawModelData {
@@ -86,7 +101,7 @@ pub struct Model {
     name: String,
     pub interleaved_vertices: Vec<Vertex>,
     pub face_indices: Vec<u16>,
-    index_map: HashMap<u64, u16>
+    index_map: HashMap<(u64, u64, Option<u64>), u16>
 }
 
 impl Model {
@@ -103,8 +118,10 @@ impl Model {
         &self.name
     }
 
-    pub fn get_index(&mut self, index_position: u64, index_normal: u64, index_tex_coord: u64, vertex: Vertex) -> u16 {
-        let identifier: u64 = index_tex_coord + (index_normal << 16) + (index_position << 32);
+    /// Find the index of the vertex with the given source indices, adding it if it's new. Vertices
+    /// without texture coordinates are distinct from those with any texture coordinate index.
+    pub fn get_index(&mut self, index_position: u64, index_normal: u64, index_tex_coord: Option<u64>, vertex: Vertex) -> u16 {
+        let identifier = (index_position, index_normal, index_tex_coord);
         match self.index_map.get(&identifier) {
             Some(position) => {
                 *position
@@ -126,7 +143,7 @@ impl Model {
 
     /// # Safety
     /// Should be safe to use - current self should have well-formed vertex data Vecs
-    pub unsafe fn write_data_to_file(&self, file: &mut File) -> std::io::Result<()> {
+    pub unsafe fn write_data_to_file<W: Write>(&self, file: &mut W) -> std::io::Result<()> {
         file.write_all(&FILE_VERSION_NUMBER.to_ne_bytes())?;
 
         let vertex_count = self.interleaved_vertices.len() as u32;
@@ -145,38 +162,63 @@ impl Model {
     }
 
     /// # Safety
-    /// Should be safe if processing files generated with the same version of this tool
+    /// Should be safe if processing files generated with the same version of this tool. Panics
+    /// if the data is malformed; use try_from_bytes for data that may not be.
     pub unsafe fn from_bytes(bytes: &[u8]) -> Model {
-        let version_ptr = bytes.as_ptr();
+        Self::try_from_bytes(bytes).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Decode data written by write_data_to_file, failing if it is truncated, refers to vertices
+    /// that aren't there or is from a different version of this tool
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Model, ConversionError> {
+        let malformed = || ConversionError::Parse(String::from("Malformed model data"));
+        let read_u32 = |offset: usize| -> Result<u32, ConversionError> {
+            let word = bytes.get(offset..(offset + 4)).ok_or_else(malformed)?;
+            Ok(u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+        };
 
-        let version_number = *(version_ptr as *const u32);
+        let version_number = read_u32(0)?;
         if version_number != FILE_VERSION_NUMBER {
-            panic!("Bad file version: expected {} but was {}", FILE_VERSION_NUMBER, version_number);
+            return Err(ConversionError::Parse(format!("Bad file version: expected {} but was {}", FILE_VERSION_NUMBER, version_number)));
         }
 
-        let vertex_count_ptr = bytes[4..8].as_ptr();
-        let vertex_count = *(vertex_count_ptr as *const u32);
-        let mut interleaved_vertices: Vec<Vertex> = vec![Vertex::new_empty(); vertex_count as usize];
-        let vertex_data_ptr = bytes[8..(8 + vertex_count as usize * 8 * 4)].as_ptr();
-        let vertex_ptr = vertex_data_ptr as *const Vertex;
-        let vertex_slice = std::slice::from_raw_parts(vertex_ptr, vertex_count as usize);
-        interleaved_vertices.copy_from_slice(vertex_slice);
-
-        let face_count_offset = (8 + vertex_count * 8 * 4) as usize;
-        let face_count_ptr = bytes[face_count_offset..(face_count_offset + 4)].as_ptr();
-        let face_count = *(face_count_ptr as *const u32);
-        let mut face_indices: Vec<u16> = vec![0u16; (face_count * 3) as usize];
-        let face_data_ptr = bytes[(face_count_offset + 4)..].as_ptr();
-        let face_ptr = face_data_ptr as *const u16;
-        let face_slice = std::slice::from_raw_parts(face_ptr, (face_count * 3) as usize);
-        face_indices.copy_from_slice(face_slice);
+        let vertex_count = read_u32(4)? as usize;
+        let vertex_data_end = vertex_count.checked_mul(8 * 4)
+            .and_then(|length| length.checked_add(8))
+            .ok_or_else(malformed)?;
+        let vertex_data = bytes.get(8..vertex_data_end).ok_or_else(malformed)?;
+        let interleaved_vertices: Vec<Vertex> = vertex_data.chunks_exact(8 * 4)
+            .map(|vertex_bytes| {
+                let mut components = [0f32; 8];
+                for (component, word) in components.iter_mut().zip(vertex_bytes.chunks_exact(4)) {
+                    *component = f32::from_ne_bytes([word[0], word[1], word[2], word[3]]);
+                }
+                Vertex::from_components(
+                    &[components[0], components[1], components[2]],
+                    &[components[3], components[4], components[5]],
+                    &[components[6], components[7]])
+            })
+            .collect();
+
+        let face_count = read_u32(vertex_data_end)? as usize;
+        let face_data_start = vertex_data_end + 4;
+        let face_data_end = face_count.checked_mul(3 * 2)
+            .and_then(|length| length.checked_add(face_data_start))
+            .ok_or_else(malformed)?;
+        let face_data = bytes.get(face_data_start..face_data_end).ok_or_else(malformed)?;
+        let face_indices: Vec<u16> = face_data.chunks_exact(2)
+            .map(|index| u16::from_ne_bytes([index[0], index[1]]))
+            .collect();
+        if face_indices.iter().any(|index| *index as usize >= vertex_count) {
+            return Err(malformed());
+        }
 
-        Model {
+        Ok(Model {
             name: String::from(""),
             interleaved_vertices,
             face_indices,
             index_map: HashMap::new()
-        }
+        })
     }
 }
 
@@ -1,70 +1,190 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs;
 use std::path::PathBuf;
-use std::str::Lines;
+use std::str::{Lines, SplitWhitespace, FromStr};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
-use crate::model::{RawModelData, Model, Vertex};
-use std::fs::File;
-use crate::collisiondata::{CollisionData, Surface, Vec3, WALL_NORMAL_ELEVATION_MIN, WALL_NORMAL_ELEVATION_MAX, SLIDE_NORMAL_ELEVATION_MIN, SLIDE_NORMAL_ELEVATION_MAX, Wall};
+use crate::model::{RawModelData, Model, Vertex, MAX_MDL_VERTICES};
+use std::io::Write;
+use crate::collisiondata::{CollisionData, Surface, Vec3, Wall, Classification, ClassifiedSurface};
+use crate::config::{ClassificationOverrides, IgnoreList};
+use crate::conversion::{ConversionError, ConversionOutput, StagedFiles, is_cancelled};
+use crate::compactmodel::{CompactModel, SizeReport, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES};
 
-pub const FILE_VERSION_NUMBER: u32 = 1;
+pub const FILE_VERSION_NUMBER: u32 = 3;
 
 const KEY_OBJECT: &str = "o";
 const KEY_VERTEX: &str = "v";
 const KEY_NORMAL: &str = "vn";
 const KEY_TEX_COORD: &str = "vt";
 const KEY_FACE: &str = "f";
+const KEY_USE_MATERIAL: &str = "usemtl";
 
+/// Zero-based indices for one face vertex. Texture coordinates are optional; vertices without
+/// them get zero texture coordinates.
 struct IndexSet {
-    position_index: u16,
-    normal_index: u16,
-    tex_coord_index: u16
+    position_index: u32,
+    normal_index: u32,
+    tex_coord_index: Option<u32>
+}
+
+/// A model as it is read from a source file. Faces are indexed with 32 bits while reading, so a
+/// model with more vertices than 16-bit indices can address can still be split up by the mobile
+/// profile. Models that fit become a Model once read; larger ones are kept in this form.
+pub(crate) struct ExtractedModel {
+    pub(crate) name: String,
+    pub(crate) interleaved_vertices: Vec<Vertex>,
+    pub(crate) face_indices: Vec<u32>,
+    index_map: HashMap<(u32, u32, Option<u32>), u32>
+}
+
+impl ExtractedModel {
+    fn new(model_name: String) -> ExtractedModel {
+        ExtractedModel {
+            name: model_name,
+            interleaved_vertices: vec![],
+            face_indices: vec![],
+            index_map: HashMap::new()
+        }
+    }
+
+    /// Find the index of the vertex with the given source indices, adding it if it's new
+    fn get_index(&mut self, index_position: u32, index_normal: u32, index_tex_coord: Option<u32>, vertex: Vertex) -> u32 {
+        let identifier = (index_position, index_normal, index_tex_coord);
+        match self.index_map.get(&identifier) {
+            Some(position) => {
+                *position
+            },
+            None => {
+                let new_index = self.interleaved_vertices.len() as u32;
+                self.index_map.insert(identifier, new_index);
+                self.interleaved_vertices.push(vertex);
+                new_index
+            }
+        }
+    }
+
+    fn add_face(&mut self, indices: [u32; 3]) {
+        self.face_indices.push(indices[0]);
+        self.face_indices.push(indices[1]);
+        self.face_indices.push(indices[2]);
+    }
+
+    /// Narrow the indices to 16 bits, or give the model back if it has too many vertices for that
+    fn into_model(self) -> Result<Model, ExtractedModel> {
+        if self.interleaved_vertices.len() > MAX_MDL_VERTICES {
+            return Err(self);
+        }
+        let mut model = Model::new(self.name);
+        model.interleaved_vertices = self.interleaved_vertices;
+        model.face_indices = self.face_indices.iter().map(|index| *index as u16).collect();
+        Ok(model)
+    }
 }
 
 pub struct ModelFactory {
     source_file_path: PathBuf,
     raw_model_data: RawModelData,
     models: Vec<Model>,
-    collision_data: Vec<CollisionData>
+    large_models: Vec<ExtractedModel>,
+    collision_data: Vec<CollisionData>,
+    classification_overrides: ClassificationOverrides,
+    current_material: Option<String>,
+    cancellation_token: Option<Arc<AtomicBool>>,
+    ignore_list: IgnoreList
 }
 
 impl ModelFactory {
-    pub fn new(file_path: PathBuf) -> ModelFactory {
+    pub fn new(file_path: PathBuf, classification_overrides: ClassificationOverrides) -> ModelFactory {
         ModelFactory {
             source_file_path: file_path,
             raw_model_data: RawModelData::default(),
             models: vec![],
-            collision_data: vec![]
+            large_models: vec![],
+            collision_data: vec![],
+            classification_overrides,
+            current_material: None,
+            cancellation_token: None,
+            ignore_list: IgnoreList::default()
+        }
+    }
+
+    /// Models matching the ignore list are still parsed but are skipped by export_all
+    pub fn set_ignore_list(&mut self, ignore_list: IgnoreList) {
+        self.ignore_list = ignore_list;
+    }
+
+    /// Allow extraction and export to be abandoned part-way through by setting the token
+    pub fn set_cancellation_token(&mut self, cancellation_token: Arc<AtomicBool>) {
+        self.cancellation_token = Some(cancellation_token);
+    }
+
+    /// Models with more than MAX_MDL_VERTICES vertices can't be indexed with 16 bits, so they
+    /// are left out here; only the mobile profile can export them
+    pub fn get_models(&self) -> &Vec<Model> {
+        &self.models
+    }
+
+    /// The models left out of get_models for having too many vertices
+    pub(crate) fn get_large_models(&self) -> &Vec<ExtractedModel> {
+        &self.large_models
+    }
+
+    /// The names of all models read, including those too large for get_models
+    pub(crate) fn get_model_names(&self) -> Vec<String> {
+        self.models.iter().map(|model| model.get_name().clone())
+            .chain(self.large_models.iter().map(|model| model.name.clone()))
+            .collect()
+    }
+
+    pub fn get_collision_data(&self) -> &Vec<CollisionData> {
+        &self.collision_data
+    }
+
+    fn check_cancelled(&self) -> Result<(), ConversionError> {
+        if is_cancelled(&self.cancellation_token) {
+            return Err(ConversionError::Cancelled);
         }
+        Ok(())
     }
 
-    /// Find the Vertex data for an index set (panics if the vertex data isn't found)
-    fn vertex_from_indices(&self, indices: &IndexSet) -> Vertex {
-        let position = self.raw_model_data.get_raw_position(indices.position_index).unwrap();
-        let normal = self.raw_model_data.get_raw_normal(indices.normal_index).unwrap();
-        let tex_coord = self.raw_model_data.get_raw_tex_coord(indices.tex_coord_index).unwrap();
-        Vertex::from_components(&position, &normal, &tex_coord)
+    /// Find the Vertex data for an index set (errors if the vertex data isn't found)
+    fn vertex_from_indices(&self, indices: &IndexSet) -> Result<Vertex, ConversionError> {
+        let position = self.raw_model_data.get_raw_position(indices.position_index)
+            .ok_or_else(|| ConversionError::Parse(format!("Position index out of range: {}", indices.position_index + 1)))?;
+        let normal = self.raw_model_data.get_raw_normal(indices.normal_index)
+            .ok_or_else(|| ConversionError::Parse(format!("Normal index out of range: {}", indices.normal_index + 1)))?;
+        let tex_coord = match indices.tex_coord_index {
+            Some(index) => *self.raw_model_data.get_raw_tex_coord(index)
+                .ok_or_else(|| ConversionError::Parse(format!("Texture coordinate index out of range: {}", index + 1)))?,
+            None => [0.0, 0.0]
+        };
+        Ok(Vertex::from_components(position, normal, &tex_coord))
     }
 
     /// Given n index sets, generate n-2 faces (triangles)
-    fn add_faces_for_index_sets(&self, index_sets: &Vec<IndexSet>, model: &mut Model) {
-        let start_index: u16 = {
+    fn add_faces_for_index_sets(&self, index_sets: &Vec<IndexSet>, model: &mut ExtractedModel) -> Result<(), ConversionError> {
+        let start_index: u32 = {
             let grouping = &index_sets[0];
-            let vertex = self.vertex_from_indices(grouping);
-            model.get_index(grouping.position_index as u64, grouping.normal_index as u64, grouping.tex_coord_index as u64, vertex)
+            let vertex = self.vertex_from_indices(grouping)?;
+            model.get_index(grouping.position_index, grouping.normal_index, grouping.tex_coord_index, vertex)
         };
 
-        let mut second_index: u16 = {
+        let mut second_index: u32 = {
             let grouping = &index_sets[1];
-            let vertex = self.vertex_from_indices(grouping);
-            model.get_index(grouping.position_index as u64, grouping.normal_index as u64, grouping.tex_coord_index as u64, vertex)
+            let vertex = self.vertex_from_indices(grouping)?;
+            model.get_index(grouping.position_index, grouping.normal_index, grouping.tex_coord_index, vertex)
         };
 
         for grouping in index_sets.iter().take(index_sets.len()).skip(2) {
-            let vertex = self.vertex_from_indices(grouping);
-            let third_index = model.get_index(grouping.position_index as u64, grouping.normal_index as u64, grouping.tex_coord_index as u64, vertex);
+            let vertex = self.vertex_from_indices(grouping)?;
+            let third_index = model.get_index(grouping.position_index, grouping.normal_index, grouping.tex_coord_index, vertex);
             model.add_face([start_index, second_index, third_index]);
             second_index = third_index;
         }
+        Ok(())
     }
 
     /// If there are 3 or 4 index sets, generate collision data
@@ -72,14 +192,15 @@ impl ModelFactory {
     /// or walls (one per quad if possible, else one per triangle). Since triangles may form quads
     /// without being stored in the source data as quads, the final wall data should be passed over
     /// to merge walls that look to be duplicates of each other.
-    fn add_collisions_for_index_sets(&self, index_sets: &Vec<IndexSet>, collision_data: &mut CollisionData) {
+    /// If an override classification is given, it is used for every polygon regardless of angle.
+    fn add_collisions_for_index_sets(&self, index_sets: &Vec<IndexSet>, classification_override: Option<Classification>, collision_data: &mut CollisionData) -> Result<(), ConversionError> {
 
         let vertices: Vec<Vertex> = index_sets.iter()
             .map(|set| self.vertex_from_indices(set))
-            .collect();
+            .collect::<Result<Vec<Vertex>, ConversionError>>()?;
         let polygon_count = vertices.len() as isize - 2;
         if polygon_count < 1 {
-            return;
+            return Ok(());
         }
         let polygon_count = polygon_count as usize;
 
@@ -109,9 +230,9 @@ impl ModelFactory {
 
         // Check for special case where there are 2 polygons and they both have a wall-oriented normal
         let make_wall_from_quad = if all_surfaces.len() == 2 {
-            let angle_1 = all_surfaces[0].1;
-            let angle_2 = all_surfaces[1].1;
-            angle_1 > WALL_NORMAL_ELEVATION_MIN && angle_1 < WALL_NORMAL_ELEVATION_MAX && angle_2 > WALL_NORMAL_ELEVATION_MIN && angle_2 < WALL_NORMAL_ELEVATION_MAX
+            let class_1 = classification_override.unwrap_or_else(|| self.classification_overrides.classify_by_elevation(all_surfaces[0].1));
+            let class_2 = classification_override.unwrap_or_else(|| self.classification_overrides.classify_by_elevation(all_surfaces[1].1));
+            class_1 == Classification::Wall && class_2 == Classification::Wall
         } else {
             false
         };
@@ -132,36 +253,39 @@ impl ModelFactory {
             left_extreme_point.y = Self::min_of_4(points[0].y, points[1].y, points[2].y, points[3].y);
             let mut right_extreme_point = *points[Self::max_point_of_4_in_direction(&right_direction, &points)];
             right_extreme_point.y = Self::max_of_4(points[0].y, points[1].y, points[2].y, points[3].y);
-            let wall = Wall::from_bottom_left_to_top_right(left_extreme_point, right_extreme_point);
-            collision_data.walls.push(wall);
-            return
+            let wall = Wall::from_corners_facing(left_extreme_point, right_extreme_point, approx_wall_normal);
+            collision_data.add_wall(wall, all_surfaces[0].0.winding_normal() + all_surfaces[1].0.winding_normal());
+            return Ok(());
         }
 
         // For each polygon, add to collision data whatever kind of wall or surface it is
         for surface in all_surfaces.iter() {
-            let angle = surface.1;
-            if angle > WALL_NORMAL_ELEVATION_MIN && angle < WALL_NORMAL_ELEVATION_MAX {
-                let points: [&Vec3; 3] = [
-                    &surface.0.point_0,
-                    &surface.0.point_1,
-                    &surface.0.point_2
-                ];
-                let mut approx_wall_normal = surface.0.normal;
-                approx_wall_normal.y = 0.0;
-                let left_direction = Vec3 { x: -approx_wall_normal.z, y: 0.0, z: approx_wall_normal.x };
-                let right_direction = Vec3 { x: approx_wall_normal.z, y: 0.0, z: -approx_wall_normal.x };
-                let mut left_extreme_point = *points[Self::max_point_of_3_in_direction(&left_direction, &points)];
-                left_extreme_point.y = Self::min_of_3(points[0].y, points[1].y, points[2].y);
-                let mut right_extreme_point = *points[Self::max_point_of_3_in_direction(&right_direction, &points)];
-                right_extreme_point.y = Self::max_of_3(points[0].y, points[1].y, points[2].y);
-                let wall = Wall::from_bottom_left_to_top_right(left_extreme_point, right_extreme_point);
-                collision_data.walls.push(wall);
-            } else if angle < SLIDE_NORMAL_ELEVATION_MAX && angle > SLIDE_NORMAL_ELEVATION_MIN {
-                collision_data.sliding_surfaces.push(surface.0);
-            } else {
-                collision_data.traction_surfaces.push(surface.0);
+            let classification = classification_override.unwrap_or_else(|| self.classification_overrides.classify_by_elevation(surface.1));
+            match classification {
+                Classification::Wall => {
+                    let points: [&Vec3; 3] = [
+                        &surface.0.point_0,
+                        &surface.0.point_1,
+                        &surface.0.point_2
+                    ];
+                    let mut approx_wall_normal = surface.0.normal;
+                    approx_wall_normal.y = 0.0;
+                    let left_direction = Vec3 { x: -approx_wall_normal.z, y: 0.0, z: approx_wall_normal.x };
+                    let right_direction = Vec3 { x: approx_wall_normal.z, y: 0.0, z: -approx_wall_normal.x };
+                    let mut left_extreme_point = *points[Self::max_point_of_3_in_direction(&left_direction, &points)];
+                    left_extreme_point.y = Self::min_of_3(points[0].y, points[1].y, points[2].y);
+                    let mut right_extreme_point = *points[Self::max_point_of_3_in_direction(&right_direction, &points)];
+                    right_extreme_point.y = Self::max_of_3(points[0].y, points[1].y, points[2].y);
+                    let wall = Wall::from_corners_facing(left_extreme_point, right_extreme_point, approx_wall_normal);
+                    collision_data.add_wall(wall, surface.0.winding_normal());
+                },
+                Classification::Sliding => collision_data.sliding_surfaces.push(surface.0),
+                Classification::Traction => collision_data.traction_surfaces.push(surface.0),
+                Classification::Climbable => collision_data.climbable_surfaces.push(surface.0),
+                Classification::Custom(_) => collision_data.custom_surfaces.push(ClassifiedSurface::new(classification, surface.0))
             }
         }
+        Ok(())
     }
 
     fn min_of_3(val0: f32, val1: f32, val2: f32) -> f32 {
@@ -255,10 +379,46 @@ impl ModelFactory {
         max_index
     }
 
-    fn extract_next_model_from_stream(&mut self, model_name: String, lines_iter: &mut Lines, include_collisions: bool) -> Option<String> {
-        let mut model = Model::new(model_name.clone());
+    /// Parse the next whitespace-separated value on a line
+    fn parse_next<T: FromStr>(line_parts: &mut SplitWhitespace, line: &str) -> Result<T, ConversionError> {
+        line_parts.next()
+            .and_then(|part| part.parse::<T>().ok())
+            .ok_or_else(|| ConversionError::Parse(format!("Malformed line: {}", line)))
+    }
+
+    /// Parse a face vertex in the form position/tex_coord/normal or position//normal, converting
+    /// to zero-based indices. Negative indices count back from the most recently read element.
+    fn parse_index_set(&self, grouping: &str) -> Result<IndexSet, ConversionError> {
+        let parse_index = |text: &str, element_count: usize| -> Option<u32> {
+            let index = text.parse::<i64>().ok()?;
+            let zero_based_index = if index < 0 { element_count as i64 + index } else { index - 1 };
+            u32::try_from(zero_based_index).ok()
+        };
+        let index_set = grouping.find('/').and_then(|first_slash| {
+            let second_slash = grouping.rfind('/')?;
+            if second_slash == first_slash {
+                return None;
+            }
+            let tex_coord_text = grouping.get((first_slash + 1)..second_slash)?;
+            let tex_coord_index = if tex_coord_text.is_empty() {
+                None
+            } else {
+                Some(parse_index(tex_coord_text, self.raw_model_data.tex_coord_count())?)
+            };
+            Some(IndexSet {
+                position_index: parse_index(&grouping[0..first_slash], self.raw_model_data.position_count())?,
+                tex_coord_index,
+                normal_index: parse_index(&grouping[(second_slash + 1)..], self.raw_model_data.normal_count())?
+            })
+        });
+        index_set.ok_or_else(|| ConversionError::Parse(format!("Malformed face vertex: {}", grouping)))
+    }
+
+    fn extract_next_model_from_stream(&mut self, model_name: String, lines_iter: &mut Lines, include_collisions: bool) -> Result<Option<String>, ConversionError> {
+        let mut model = ExtractedModel::new(model_name.clone());
         let mut collision_data = CollisionData::new(model_name);
         for l in lines_iter {
+            self.check_cancelled()?;
             let mut line_parts = l.split_whitespace();
             let key = match line_parts.next() {
                 Some(k) => k,
@@ -266,60 +426,82 @@ impl ModelFactory {
             };
             match key {
                 KEY_VERTEX => {
-                    let x: f32 = line_parts.next().unwrap().parse().unwrap();
-                    let y: f32 = line_parts.next().unwrap().parse().unwrap();
-                    let z: f32 = line_parts.next().unwrap().parse().unwrap();
+                    let x: f32 = Self::parse_next(&mut line_parts, l)?;
+                    let y: f32 = Self::parse_next(&mut line_parts, l)?;
+                    let z: f32 = Self::parse_next(&mut line_parts, l)?;
                     self.raw_model_data.push_position([x, y, z]);
                 },
                 KEY_NORMAL => {
-                    let x: f32 = line_parts.next().unwrap().parse().unwrap();
-                    let y: f32 = line_parts.next().unwrap().parse().unwrap();
-                    let z: f32 = line_parts.next().unwrap().parse().unwrap();
+                    let x: f32 = Self::parse_next(&mut line_parts, l)?;
+                    let y: f32 = Self::parse_next(&mut line_parts, l)?;
+                    let z: f32 = Self::parse_next(&mut line_parts, l)?;
                     self.raw_model_data.push_normal([x, y, z]);
                 },
                 KEY_TEX_COORD => {
-                    let s: f32 = line_parts.next().unwrap().parse().unwrap();
-                    let t: f32 = line_parts.next().unwrap().parse().unwrap();
+                    let s: f32 = Self::parse_next(&mut line_parts, l)?;
+                    let t: f32 = Self::parse_next(&mut line_parts, l)?;
                     self.raw_model_data.push_tex_coord([s, t]);
                 },
                 KEY_FACE => {
-                    let mut index_sets: Vec<IndexSet> = vec![];
-                    while let Some(grouping) = line_parts.next() {
-                        let first_slash = grouping.find('/').unwrap();
-                        let second_slash = grouping.rfind('/').unwrap();
-                        let position_index: u16 = grouping[0..first_slash].parse::<u16>().unwrap() - 1;
-                        let tex_coord_index: u16 = grouping[(first_slash + 1)..second_slash].parse::<u16>().unwrap() - 1;
-                        let normal_index: u16 = grouping[(second_slash + 1)..].parse::<u16>().unwrap() - 1;
-                        index_sets.push(IndexSet { position_index, normal_index, tex_coord_index });
+                    let index_sets: Vec<IndexSet> = line_parts
+                        .map(|grouping| self.parse_index_set(grouping))
+                        .collect::<Result<Vec<IndexSet>, ConversionError>>()?;
+                    if index_sets.len() < 3 {
+                        return Err(ConversionError::Parse(format!("Face has fewer than 3 vertices: {}", l)));
                     }
 
-                    self.add_faces_for_index_sets(&index_sets, &mut model);
+                    self.add_faces_for_index_sets(&index_sets, &mut model)?;
                     if include_collisions {
-                        self.add_collisions_for_index_sets(&index_sets, &mut collision_data);
+                        let classification_override = self.classification_overrides.find(
+                            collision_data.get_model_name(),
+                            self.current_material.as_deref());
+                        self.add_collisions_for_index_sets(&index_sets, classification_override, &mut collision_data)?;
                     }
                 },
+                KEY_USE_MATERIAL => {
+                    self.current_material = line_parts.next().map(String::from);
+                },
                 KEY_OBJECT => {
-                    self.models.push(model);
+                    Self::finish_collision_data(&mut collision_data);
+                    self.finish_model(model);
                     self.collision_data.push(collision_data);
                     let model_name = match line_parts.next() {
                         Some(name) => name,
-                        None => panic!("No model name found!")
+                        None => return Err(ConversionError::Parse(String::from("No model name found")))
                     };
-                    return Some(String::from(model_name));
+                    return Ok(Some(String::from(model_name)));
                 },
                 _ => ()
             }
         }
+        Self::finish_collision_data(&mut collision_data);
+        self.finish_model(model);
+        self.collision_data.push(collision_data);
+        Ok(None)
+    }
+
+    fn finish_model(&mut self, model: ExtractedModel) {
+        match model.into_model() {
+            Ok(model) => self.models.push(model),
+            Err(large_model) => self.large_models.push(large_model)
+        }
+    }
+
+    /// Post-process the collision data for a model once all of its faces have been read
+    fn finish_collision_data(collision_data: &mut CollisionData) {
         collision_data.remove_wall_duplicates();
         collision_data.find_extents();
-        self.models.push(model);
-        self.collision_data.push(collision_data);
-        None
     }
 
-    pub fn extract_all_models_from_file(&mut self, include_collisions: bool) {
-        let file_contents = fs::read_to_string(&self.source_file_path).unwrap();
-        let mut lines_iter = file_contents.lines();
+    pub fn extract_all_models_from_file(&mut self, include_collisions: bool) -> Result<(), ConversionError> {
+        let file_contents = fs::read_to_string(&self.source_file_path)
+            .map_err(|e| ConversionError::Io(self.source_file_path.clone(), e))?;
+        self.extract_all_models_from_str(&file_contents, include_collisions)
+    }
+
+    /// Parse OBJ source that has already been read into memory
+    pub fn extract_all_models_from_str(&mut self, source: &str, include_collisions: bool) -> Result<(), ConversionError> {
+        let mut lines_iter = source.lines();
         while let Some(l) = lines_iter.next() {
             let line = l.trim();
             if line.is_empty() {
@@ -330,10 +512,10 @@ impl ModelFactory {
                 if part == KEY_OBJECT {
                     let mut model_name = match line_parts.next() {
                         Some(name) => String::from(name),
-                        None => panic!("No model name found!")
+                        None => return Err(ConversionError::Parse(String::from("No model name found")))
                     };
                     loop {
-                        model_name = match self.extract_next_model_from_stream(model_name, &mut lines_iter, include_collisions) {
+                        model_name = match self.extract_next_model_from_stream(model_name, &mut lines_iter, include_collisions)? {
                             Some(name) => name,
                             None => break
                         };
@@ -342,41 +524,99 @@ impl ModelFactory {
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn export_all(&self, dst_path: &PathBuf, collision_maps_path: Option<&PathBuf>) {
-        println!("Files written:");
+    /// Write out all models, and collision data if a directory is given for it, returning the
+    /// paths of the files written. With the mobile profile, models are written in the compact
+    /// format instead, and the size reduction for each is reported. Files are only moved into
+    /// place once all of them have been written. Collision data with walls facing against the
+    /// winding order of their faces is still written, with a warning for each model affected.
+    pub fn export_all(&self, dst_path: &PathBuf, collision_maps_path: Option<&PathBuf>, mobile: bool) -> Result<ConversionOutput, ConversionError> {
+        let mut staged_files = StagedFiles::new();
+        let mut size_reports: Vec<SizeReport> = vec![];
+        let mut ignored_models: Vec<String> = vec![];
+        let mut warnings: Vec<String> = vec![];
         for model in self.models.iter() {
+            self.check_cancelled()?;
+            if self.ignore_list.ignores_object(model.get_name()) {
+                ignored_models.push(model.get_name().clone());
+                continue;
+            }
+            if mobile {
+                let compact_model = CompactModel::from_model(model, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES);
+                let size = Self::stage_compact_model(&compact_model, model.get_name(), dst_path, &mut staged_files)?;
+                size_reports.push(SizeReport::for_model(model, size));
+                continue;
+            }
+
             let mut output_file: PathBuf = dst_path.into();
             output_file.push(model.get_name());
             output_file.set_extension("mdl");
-            let mut file = File::create(output_file).unwrap();
+            let mut file = staged_files.create(&output_file)?;
             let result = unsafe {
                 model.write_data_to_file(&mut file)
             };
-            match result {
-                Ok(()) => println!(" {}.mdl", model.get_name()),
-                _ => panic!("Error writing file: {}.mdl", model.get_name())
+            if let Err(e) = result {
+                return Err(ConversionError::Io(output_file, e));
             }
         }
 
-        if collision_maps_path.is_none() {
-            return
+        for model in self.large_models.iter() {
+            self.check_cancelled()?;
+            if self.ignore_list.ignores_object(&model.name) {
+                ignored_models.push(model.name.clone());
+                continue;
+            }
+            if !mobile {
+                return Err(ConversionError::Encoding(format!(
+                    "{} has {} vertices, too many for a .mdl file; use the mobile profile instead",
+                    model.name, model.interleaved_vertices.len())));
+            }
+            let compact_model = CompactModel::from_vertices(&model.interleaved_vertices, &model.face_indices, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES);
+            let size = Self::stage_compact_model(&compact_model, &model.name, dst_path, &mut staged_files)?;
+            size_reports.push(SizeReport::for_counts(&model.name, model.interleaved_vertices.len(), model.face_indices.len(), size));
         }
-        let collision_dir = collision_maps_path.unwrap();
-        for collisions in self.collision_data.iter() {
-            let mut output_file: PathBuf = collision_dir.into();
-            output_file.push(collisions.get_model_name());
-            output_file.set_extension("csn");
-            let mut file = File::create(output_file).unwrap();
 
-            let result = unsafe {
-                collisions.write_data_to_file(&mut file)
-            };
-            match result {
-                Ok(()) => println!(" {}.csn", collisions.get_model_name()),
-                _ => panic!("Error writing file: {}.csn", collisions.get_model_name())
+        if let Some(collision_dir) = collision_maps_path {
+            for collisions in self.collision_data.iter() {
+                self.check_cancelled()?;
+                if self.ignore_list.ignores_object(collisions.get_model_name()) {
+                    continue;
+                }
+                let misoriented_walls = collisions.find_misoriented_walls();
+                if !misoriented_walls.is_empty() {
+                    warnings.push(format!("{} wall(s) in {} have normals disagreeing with the winding order of their faces",
+                        misoriented_walls.len(), collisions.get_model_name()));
+                }
+                let mut output_file: PathBuf = collision_dir.into();
+                output_file.push(collisions.get_model_name());
+                output_file.set_extension("csn");
+                let mut file = staged_files.create(&output_file)?;
+
+                let result = unsafe {
+                    collisions.write_data_to_file(&mut file)
+                };
+                if let Err(e) = result {
+                    return Err(ConversionError::Io(output_file, e));
+                }
             }
         }
+
+        self.check_cancelled()?;
+        let files_written = staged_files.commit()?;
+        Ok(ConversionOutput { files_written, size_reports, ignored_models, warnings })
+    }
+
+    /// Stage a model in the compact format, returning its size in bytes
+    fn stage_compact_model(compact_model: &CompactModel, model_name: &str, dst_path: &PathBuf, staged_files: &mut StagedFiles) -> Result<usize, ConversionError> {
+        let mut output_file: PathBuf = dst_path.into();
+        output_file.push(model_name);
+        output_file.set_extension("cmdl");
+        let bytes = compact_model.to_bytes()?;
+        let mut file = staged_files.create(&output_file)?;
+        file.write_all(&bytes)
+            .map_err(|e| ConversionError::Io(output_file.clone(), e))?;
+        Ok(bytes.len())
     }
 }
@@ -0,0 +1,236 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::collisiondata::CollisionData;
+use crate::config::{WcConfig, CONFIG_FILE_NAME};
+use crate::conversion::{ConversionOptions, ConversionError, convert_paths};
+use crate::model::Model;
+
+pub const EXPECTED_DIRECTORY_NAME: &str = "expected";
+
+/// Classification overrides used by the materials sample; written alongside the samples so the
+/// expected outputs can be reproduced by converting the directory
+const SAMPLE_CONFIG: &str = "# Material overrides used by materials.obj
+[classification.materials]
+Ladder = \"climbable\"
+Ice = \"sliding\"
+";
+
+/// A synthetic source file demonstrating one feature of the OBJ subset that can be converted.
+/// Blank lines end an object, so there are none inside the objects.
+struct Sample {
+    file_name: &'static str,
+    source: &'static str
+}
+
+const SAMPLES: [Sample; 5] = [
+    Sample {
+        file_name: "ngons.obj",
+        source: "# A single hexagonal face. Faces with more than 3 vertices are split into a fan of
+# triangles around their first vertex, giving 4 triangles and 4 traction surfaces here.
+
+o Hexagon
+v 1.0 0.0 0.0
+v 0.5 0.0 -0.866025
+v -0.5 0.0 -0.866025
+v -1.0 0.0 0.0
+v -0.5 0.0 0.866025
+v 0.5 0.0 0.866025
+vt 1.0 0.5
+vt 0.75 0.933013
+vt 0.25 0.933013
+vt 0.0 0.5
+vt 0.25 0.066987
+vt 0.75 0.066987
+vn 0.0 1.0 0.0
+f 1/1/1 2/2/1 3/3/1 4/4/1 5/5/1 6/6/1
+"
+    },
+    Sample {
+        file_name: "multiple_objects.obj",
+        source: "# Three objects in one file, each written to its own model and collision file.
+# Indices count from the start of the file rather than the start of each object, so later
+# objects can reuse texture coordinates and normals declared earlier. Collision data is
+# classified by the angle of each face: the floor gives traction surfaces, the vertical quad
+# gives a single wall, and the steep ramp gives sliding surfaces.
+
+o Floor
+v 0.0 0.0 0.0
+v 2.0 0.0 0.0
+v 2.0 0.0 -2.0
+v 0.0 0.0 -2.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 1.0 1.0
+vt 0.0 1.0
+vn 0.0 1.0 0.0
+f 1/1/1 2/2/1 3/3/1 4/4/1
+o Wall
+v 0.0 0.0 -2.0
+v 2.0 0.0 -2.0
+v 2.0 1.0 -2.0
+v 0.0 1.0 -2.0
+vn 0.0 0.0 1.0
+f 5/1/2 6/2/2 7/3/2 8/4/2
+o Ramp
+v 3.0 0.0 0.0
+v 3.0 0.0 -2.0
+v 2.5 0.866025 -2.0
+v 2.5 0.866025 0.0
+vn 0.866025 0.5 0.0
+f 9/1/3 10/2/3 11/3/3 12/4/3
+"
+    },
+    Sample {
+        file_name: "missing_uvs.obj",
+        source: "# Face vertices written as position//normal have no texture coordinates, and are given
+# texture coordinates of zero.
+
+o Plain
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 1.0 0.0 -1.0
+v 0.0 0.0 -1.0
+vn 0.0 1.0 0.0
+f 1//1 2//1 3//1 4//1
+"
+    },
+    Sample {
+        file_name: "negative_indices.obj",
+        source: "# Negative indices count back from the most recently declared element, so -1 is the last
+# position, texture coordinate or normal read so far. Both faces use the same face line but
+# refer to different positions.
+
+o Relative
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 1.0 0.0 -1.0
+v 0.0 0.0 -1.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 1.0 1.0
+vt 0.0 1.0
+vn 0.0 1.0 0.0
+f -4/-4/-1 -3/-3/-1 -2/-2/-1 -1/-1/-1
+v 2.0 0.0 0.0
+v 3.0 0.0 0.0
+v 3.0 0.0 -1.0
+v 2.0 0.0 -1.0
+f -4/-4/-1 -3/-3/-1 -2/-2/-1 -1/-1/-1
+"
+    },
+    Sample {
+        file_name: "materials.obj",
+        source: "# Materials assigned with usemtl apply to the faces that follow them. The wc.toml in this
+# directory classifies Ladder faces as climbable and Ice faces as sliding, whatever their
+# angle; Stone faces have no override, so they are classified by angle as traction surfaces.
+
+o Tower
+v 0.0 0.0 0.0
+v 2.0 0.0 0.0
+v 2.0 0.0 -2.0
+v 0.0 0.0 -2.0
+v 0.0 1.0 -2.0
+v 2.0 1.0 -2.0
+v 2.0 1.0 0.0
+v 4.0 1.0 0.0
+v 4.0 1.0 -2.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 1.0 1.0
+vt 0.0 1.0
+vn 0.0 1.0 0.0
+vn 0.0 0.0 1.0
+usemtl Stone
+f 1/1/1 2/2/1 3/3/1 4/4/1
+usemtl Ladder
+f 4/1/2 3/2/2 6/3/2 5/4/2
+usemtl Ice
+f 7/1/1 8/2/1 9/3/1 6/4/1
+"
+    }
+];
+
+/// Write the sample source files and a wc.toml into the given directory, then convert them
+/// (with collision data) into an expected subdirectory. Each sample also gets a text summary of
+/// its outputs in that subdirectory. Returns the paths of all files written.
+///
+/// Fails without writing anything if the directory already has something in it, unless force is
+/// set, in which case files with the same names are overwritten. The samples are converted with
+/// the sample config itself rather than whatever wc.toml ends up on disk.
+pub fn generate_samples(directory: &PathBuf, force: bool) -> Result<Vec<PathBuf>, ConversionError> {
+    if !force && directory.is_dir() {
+        let mut entries = fs::read_dir(directory)
+            .map_err(|e| ConversionError::Io(directory.into(), e))?;
+        if entries.next().is_some() {
+            return Err(ConversionError::Io(directory.into(), Error::new(ErrorKind::AlreadyExists, "Directory is not empty")));
+        }
+    }
+
+    let mut expected_directory: PathBuf = directory.into();
+    expected_directory.push(EXPECTED_DIRECTORY_NAME);
+    fs::create_dir_all(&expected_directory)
+        .map_err(|e| ConversionError::Io(expected_directory.clone(), e))?;
+
+    let mut files_written: Vec<PathBuf> = vec![];
+    let mut config_path: PathBuf = directory.into();
+    config_path.push(CONFIG_FILE_NAME);
+    let mut config: WcConfig = toml::from_str(SAMPLE_CONFIG)
+        .map_err(|e| ConversionError::Config(config_path.clone(), e.to_string()))?;
+    config.source_path = Some(config_path.clone());
+    write_file(&config_path, SAMPLE_CONFIG, &mut files_written)?;
+    let mut source_paths: Vec<PathBuf> = vec![];
+    for sample in SAMPLES.iter() {
+        let mut source_path: PathBuf = directory.into();
+        source_path.push(sample.file_name);
+        write_file(&source_path, sample.source, &mut files_written)?;
+        source_paths.push(source_path);
+    }
+
+    let mut options = ConversionOptions::new(expected_directory.clone());
+    options.collisions_dst_path = Some(expected_directory.clone());
+    options.config = Some(config);
+    for conversion in convert_paths(source_paths, &options) {
+        let output = conversion.result?;
+        let mut summary_path = expected_directory.clone();
+        summary_path.push(conversion.source_path.file_name().unwrap());
+        summary_path.set_extension("txt");
+        let summary = summarise_outputs(&conversion.source_path, &output.files_written)?;
+        files_written.extend(output.files_written);
+        write_file(&summary_path, &summary, &mut files_written)?;
+    }
+    Ok(files_written)
+}
+
+fn write_file(path: &PathBuf, contents: &str, files_written: &mut Vec<PathBuf>) -> Result<(), ConversionError> {
+    fs::write(path, contents)
+        .map_err(|e| ConversionError::Io(path.into(), e))?;
+    files_written.push(path.into());
+    Ok(())
+}
+
+/// Describe the converted files for one source file by reading them back
+fn summarise_outputs(source_path: &Path, output_paths: &[PathBuf]) -> Result<String, ConversionError> {
+    let mut summary = format!("Expected output of converting {} with collision data\n", source_path.file_name().unwrap().to_string_lossy());
+    for path in output_paths.iter() {
+        let bytes = fs::read(path)
+            .map_err(|e| ConversionError::Io(path.into(), e))?;
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("mdl") => {
+                let model = unsafe { Model::from_bytes(&bytes) };
+                summary.push_str(&format!("{}: {} vertices, {} triangles\n",
+                    file_name, model.interleaved_vertices.len(), model.face_indices.len() / 3));
+            },
+            Some("csn") => {
+                let collisions = unsafe { CollisionData::from_bytes(&bytes) };
+                summary.push_str(&format!("{}: {} traction surfaces, {} sliding surfaces, {} walls, {} climbable surfaces, {} custom surfaces\n",
+                    file_name, collisions.traction_surfaces.len(), collisions.sliding_surfaces.len(), collisions.walls.len(),
+                    collisions.climbable_surfaces.len(), collisions.custom_surfaces.len()));
+            },
+            _ => ()
+        }
+    }
+    Ok(summary)
+}
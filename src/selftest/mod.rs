@@ -0,0 +1,466 @@
+use std::path::PathBuf;
+
+use crate::model::{Model, Vertex};
+use crate::collisiondata::{CollisionData, Classification};
+use crate::compactmodel::{CompactModel, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES};
+use crate::config::{WcConfig, CONFIG_FILE_NAME};
+use crate::conversion::ConversionError;
+use crate::modelfactory::ModelFactory;
+
+const FLOAT_TOLERANCE: f32 = 0.001;
+
+const FLOOR_SOURCE: &str = "
+o Floor
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 0.0 0.0 -1.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 0.0 1.0
+vn 0.0 1.0 0.0
+f 1/1/1 2/2/1 3/3/1
+";
+
+const WALL_SOURCE: &str = "
+o Wall
+v -1.0 0.0 0.0
+v 1.0 0.0 0.0
+v 1.0 2.0 0.0
+v -1.0 2.0 0.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 1.0 1.0
+vt 0.0 1.0
+vn 0.0 0.0 1.0
+f 1/1/1 2/2/1 3/3/1 4/4/1
+";
+
+const SLOPE_SOURCE: &str = "
+o Slope
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 0.0 0.8660254 -0.5
+vt 0.0 0.0
+vt 1.0 0.0
+vt 0.0 1.0
+vn 0.0 0.5 0.8660254
+f 1/1/1 2/2/1 3/3/1
+";
+
+const MULTIPLE_OBJECTS_SOURCE: &str = "
+o First
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 0.0 0.0 -1.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 0.0 1.0
+vn 0.0 1.0 0.0
+f 1/1/1 2/2/1 3/3/1
+o Second
+v 2.0 0.0 0.0
+v 3.0 0.0 0.0
+v 2.0 0.0 -1.0
+f 4/1/1 5/2/1 6/3/1
+";
+
+const MATERIALS_SOURCE: &str = "
+o Ledge
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+v 0.0 0.0 -1.0
+v 1.0 0.0 -1.0
+vt 0.0 0.0
+vt 1.0 0.0
+vt 0.0 1.0
+vt 1.0 1.0
+vn 0.0 1.0 0.0
+usemtl Ladder
+f 1/1/1 2/2/1 3/3/1
+usemtl Floor
+f 2/2/1 4/4/1 3/3/1
+";
+
+const MATERIALS_CONFIG: &str = "
+[classification.categories]
+ladder = 1
+
+[classification.materials]
+Ladder = \"ladder\"
+";
+
+const CLIMBABLE_CONFIG: &str = "
+[classification.climbable]
+min_elevation = 25.0
+max_elevation = 35.0
+";
+
+const OUT_OF_RANGE_INDEX_SOURCE: &str = "
+o Broken
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+vt 0.0 0.0
+vn 0.0 1.0 0.0
+f 1/1/1 2/1/1 3/1/1
+";
+
+const DEGENERATE_FACE_SOURCE: &str = "
+o Broken
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0
+vt 0.0 0.0
+vn 0.0 1.0 0.0
+f 1/1/1 2/1/1
+";
+
+/// Known-good output for one model of a reference case. Extents aren't checked where None.
+struct ExpectedModel {
+    name: &'static str,
+    vertex_count: usize,
+    index_count: usize,
+    traction_surfaces: usize,
+    sliding_surfaces: usize,
+    wall_normals: &'static [[f32; 3]],
+    climbable_surfaces: usize,
+    custom_classifications: &'static [Classification],
+    extents: Option<[[f32; 2]; 3]>
+}
+
+/// An embedded OBJ snippet, with the wc.toml contents to convert it with, and what it should
+/// convert to
+struct ReferenceCase {
+    name: &'static str,
+    config: &'static str,
+    source: &'static str,
+    expected: &'static [ExpectedModel]
+}
+
+const REFERENCE_CASES: [ReferenceCase; 6] = [
+    ReferenceCase {
+        name: "floor triangle",
+        config: "",
+        source: FLOOR_SOURCE,
+        expected: &[ExpectedModel {
+            name: "Floor",
+            vertex_count: 3,
+            index_count: 3,
+            traction_surfaces: 1,
+            sliding_surfaces: 0,
+            wall_normals: &[],
+            climbable_surfaces: 0,
+            custom_classifications: &[],
+            extents: Some([[0.0, 1.0], [0.0, 0.0], [-1.0, 0.0]])
+        }]
+    },
+    ReferenceCase {
+        name: "wall quad",
+        config: "",
+        source: WALL_SOURCE,
+        expected: &[ExpectedModel {
+            name: "Wall",
+            vertex_count: 4,
+            index_count: 6,
+            traction_surfaces: 0,
+            sliding_surfaces: 0,
+            wall_normals: &[[0.0, 0.0, 1.0]],
+            climbable_surfaces: 0,
+            custom_classifications: &[],
+            extents: None
+        }]
+    },
+    ReferenceCase {
+        name: "sliding slope",
+        config: "",
+        source: SLOPE_SOURCE,
+        expected: &[ExpectedModel {
+            name: "Slope",
+            vertex_count: 3,
+            index_count: 3,
+            traction_surfaces: 0,
+            sliding_surfaces: 1,
+            wall_normals: &[],
+            climbable_surfaces: 0,
+            custom_classifications: &[],
+            extents: Some([[0.0, 1.0], [0.0, 0.8660254], [-0.5, 0.0]])
+        }]
+    },
+    ReferenceCase {
+        name: "climbable elevation band",
+        config: CLIMBABLE_CONFIG,
+        source: SLOPE_SOURCE,
+        expected: &[ExpectedModel {
+            name: "Slope",
+            vertex_count: 3,
+            index_count: 3,
+            traction_surfaces: 0,
+            sliding_surfaces: 0,
+            wall_normals: &[],
+            climbable_surfaces: 1,
+            custom_classifications: &[],
+            extents: Some([[0.0, 1.0], [0.0, 0.8660254], [-0.5, 0.0]])
+        }]
+    },
+    ReferenceCase {
+        name: "multiple objects",
+        config: "",
+        source: MULTIPLE_OBJECTS_SOURCE,
+        expected: &[
+            ExpectedModel {
+                name: "First",
+                vertex_count: 3,
+                index_count: 3,
+                traction_surfaces: 1,
+                sliding_surfaces: 0,
+                wall_normals: &[],
+                climbable_surfaces: 0,
+                custom_classifications: &[],
+                extents: Some([[0.0, 1.0], [0.0, 0.0], [-1.0, 0.0]])
+            },
+            ExpectedModel {
+                name: "Second",
+                vertex_count: 3,
+                index_count: 3,
+                traction_surfaces: 1,
+                sliding_surfaces: 0,
+                wall_normals: &[],
+                climbable_surfaces: 0,
+                custom_classifications: &[],
+                // Extents are found starting from the origin, which this model doesn't touch, so
+                // they don't describe it
+                extents: None
+            }
+        ]
+    },
+    ReferenceCase {
+        name: "material overrides",
+        config: MATERIALS_CONFIG,
+        source: MATERIALS_SOURCE,
+        expected: &[ExpectedModel {
+            name: "Ledge",
+            vertex_count: 4,
+            index_count: 6,
+            traction_surfaces: 1,
+            sliding_surfaces: 0,
+            wall_normals: &[],
+            climbable_surfaces: 0,
+            custom_classifications: &[Classification::Custom(1)],
+            extents: Some([[0.0, 1.0], [0.0, 0.0], [-1.0, 0.0]])
+        }]
+    }
+];
+
+const MALFORMED_CASES: [(&str, &str); 2] = [
+    ("out of range index", OUT_OF_RANGE_INDEX_SOURCE),
+    ("face with two vertices", DEGENERATE_FACE_SOURCE)
+];
+
+/// The outcome of one self-test case; it passed if nothing was recorded in failures
+#[derive(Clone, Debug)]
+pub struct SelfTestResult {
+    pub name: &'static str,
+    pub failures: Vec<String>
+}
+
+impl SelfTestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Convert each embedded reference snippet in memory and compare the models, collision data and
+/// compact models against known-good values, after writing each out and reading it back. Also
+/// checks that malformed snippets are rejected with parse errors.
+pub fn run_self_test() -> Vec<SelfTestResult> {
+    let mut results: Vec<SelfTestResult> = REFERENCE_CASES.iter()
+        .map(|case| SelfTestResult { name: case.name, failures: check_reference_case(case) })
+        .collect();
+    for (name, source) in MALFORMED_CASES.iter() {
+        let failures = match convert_in_memory(name, "", source) {
+            Err(ConversionError::Parse(_)) => vec![],
+            Err(e) => vec![format!("expected a parse error, got: {}", e)],
+            Ok(_) => vec![String::from("expected a parse error, but conversion succeeded")]
+        };
+        results.push(SelfTestResult { name, failures });
+    }
+    results
+}
+
+fn convert_in_memory(name: &str, config: &str, source: &str) -> Result<ModelFactory, ConversionError> {
+    let config: WcConfig = toml::from_str(config)
+        .map_err(|e| ConversionError::Config(PathBuf::from(CONFIG_FILE_NAME), e.to_string()))?;
+    let mut factory = ModelFactory::new(PathBuf::from(name), config.classification_overrides()?);
+    factory.extract_all_models_from_str(source, true)?;
+    Ok(factory)
+}
+
+fn check_reference_case(case: &ReferenceCase) -> Vec<String> {
+    let factory = match convert_in_memory(case.name, case.config, case.source) {
+        Ok(factory) => factory,
+        Err(e) => return vec![format!("conversion failed: {}", e)]
+    };
+    let models = factory.get_models();
+    let collision_data = factory.get_collision_data();
+    if models.len() != case.expected.len() {
+        return vec![format!("expected {} models, got {}", case.expected.len(), models.len())];
+    }
+
+    let mut failures: Vec<String> = vec![];
+    for ((expected, model), collisions) in case.expected.iter().zip(models.iter()).zip(collision_data.iter()) {
+        if model.get_name() != expected.name {
+            failures.push(format!("expected model {}, got {}", expected.name, model.get_name()));
+            continue;
+        }
+        check_model(expected, model, &mut failures);
+        check_collisions(expected, collisions, &mut failures);
+        check_compact_model(expected, model, &mut failures);
+    }
+    failures
+}
+
+fn check_model(expected: &ExpectedModel, model: &Model, failures: &mut Vec<String>) {
+    if model.interleaved_vertices.len() != expected.vertex_count {
+        failures.push(format!("{}: expected {} vertices, got {}", expected.name, expected.vertex_count, model.interleaved_vertices.len()));
+    }
+    if model.face_indices.len() != expected.index_count {
+        failures.push(format!("{}: expected {} indices, got {}", expected.name, expected.index_count, model.face_indices.len()));
+    }
+
+    let mut bytes: Vec<u8> = vec![];
+    if let Err(e) = unsafe { model.write_data_to_file(&mut bytes) } {
+        failures.push(format!("{}: couldn't write model: {}", expected.name, e));
+        return;
+    }
+    let read_back = match Model::try_from_bytes(&bytes) {
+        Ok(read_back) => read_back,
+        Err(e) => {
+            failures.push(format!("{}: couldn't read model back: {}", expected.name, e));
+            return;
+        }
+    };
+    if read_back.interleaved_vertices != model.interleaved_vertices || read_back.face_indices != model.face_indices {
+        failures.push(format!("{}: model changed after writing and reading back", expected.name));
+    }
+}
+
+fn check_collisions(expected: &ExpectedModel, collisions: &CollisionData, failures: &mut Vec<String>) {
+    let counts = [
+        ("traction surfaces", expected.traction_surfaces, collisions.traction_surfaces.len()),
+        ("sliding surfaces", expected.sliding_surfaces, collisions.sliding_surfaces.len()),
+        ("walls", expected.wall_normals.len(), collisions.walls.len()),
+        ("climbable surfaces", expected.climbable_surfaces, collisions.climbable_surfaces.len()),
+        ("custom surfaces", expected.custom_classifications.len(), collisions.custom_surfaces.len())
+    ];
+    for (kind, expected_count, count) in counts.iter() {
+        if expected_count != count {
+            failures.push(format!("{}: expected {} {}, got {}", expected.name, expected_count, kind, count));
+        }
+    }
+
+    for (expected_normal, wall) in expected.wall_normals.iter().zip(collisions.walls.iter()) {
+        let normal = wall.get_normal();
+        if !approx_eq(&[normal.x, normal.y, normal.z], expected_normal) {
+            failures.push(format!("{}: expected wall normal {:?}, got {:?}", expected.name, expected_normal, normal));
+        }
+    }
+    let misoriented_walls = collisions.find_misoriented_walls();
+    if !misoriented_walls.is_empty() {
+        failures.push(format!("{}: {} wall(s) have normals disagreeing with the winding order of their faces", expected.name, misoriented_walls.len()));
+    }
+    for (expected_classification, surface) in expected.custom_classifications.iter().zip(collisions.custom_surfaces.iter()) {
+        if surface.classification() != *expected_classification {
+            failures.push(format!("{}: expected {:?} surface, got {:?}", expected.name, expected_classification, surface.classification()));
+        }
+    }
+    if let Some(extents) = expected.extents {
+        let actual_extents = [collisions.extent_x, collisions.extent_y, collisions.extent_z];
+        if !extents.iter().zip(actual_extents.iter()).all(|(expected_extent, extent)| approx_eq(expected_extent, extent)) {
+            failures.push(format!("{}: expected extents {:?}, got {:?}", expected.name, extents, actual_extents));
+        }
+    }
+
+    let mut bytes: Vec<u8> = vec![];
+    if let Err(e) = unsafe { collisions.write_data_to_file(&mut bytes) } {
+        failures.push(format!("{}: couldn't write collision data: {}", expected.name, e));
+        return;
+    }
+    let read_back = match CollisionData::try_from_bytes(&bytes) {
+        Ok(read_back) => read_back,
+        Err(e) => {
+            failures.push(format!("{}: couldn't read collision data back: {}", expected.name, e));
+            return;
+        }
+    };
+    let unchanged = read_back.extent_x == collisions.extent_x
+        && read_back.extent_y == collisions.extent_y
+        && read_back.extent_z == collisions.extent_z
+        && read_back.traction_surfaces == collisions.traction_surfaces
+        && read_back.sliding_surfaces == collisions.sliding_surfaces
+        && read_back.walls == collisions.walls
+        && read_back.climbable_surfaces == collisions.climbable_surfaces
+        && read_back.custom_surfaces == collisions.custom_surfaces;
+    if !unchanged {
+        failures.push(format!("{}: collision data changed after writing and reading back", expected.name));
+    }
+}
+
+/// The compact format reorders faces, so compare vertices one by one and faces as a set
+fn check_compact_model(expected: &ExpectedModel, model: &Model, failures: &mut Vec<String>) {
+    let compact_model = CompactModel::from_model(model, MOBILE_WELD_TOLERANCE, MAX_SUBMESH_VERTICES);
+    let bytes = match compact_model.to_bytes() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            failures.push(format!("{}: couldn't encode compact model: {}", expected.name, e));
+            return;
+        }
+    };
+    let read_back = match CompactModel::try_from_bytes(&bytes) {
+        Ok(read_back) => read_back,
+        Err(e) => {
+            failures.push(format!("{}: couldn't read compact model back: {}", expected.name, e));
+            return;
+        }
+    };
+    if read_back.submeshes.len() != compact_model.submeshes.len() {
+        failures.push(format!("{}: compact model changed after writing and reading back", expected.name));
+        return;
+    }
+
+    for (submesh, read_back_submesh) in compact_model.submeshes.iter().zip(read_back.submeshes.iter()) {
+        let canonical_faces = |face_indices: &Vec<u16>| -> Vec<[u16; 3]> {
+            let mut faces: Vec<[u16; 3]> = face_indices.chunks(3)
+                .map(|face| {
+                    let first = (0..3).min_by_key(|i| face[*i]).unwrap();
+                    [face[first], face[(first + 1) % 3], face[(first + 2) % 3]]
+                })
+                .collect();
+            faces.sort_unstable();
+            faces
+        };
+        if read_back_submesh.vertices != submesh.vertices || canonical_faces(&read_back_submesh.face_indices) != canonical_faces(&submesh.face_indices) {
+            failures.push(format!("{}: compact model changed after writing and reading back", expected.name));
+            return;
+        }
+    }
+
+    let index_count: usize = read_back.submeshes.iter().map(|submesh| submesh.face_indices.len()).sum();
+    if index_count != expected.index_count {
+        failures.push(format!("{}: expected {} compact indices, got {}", expected.name, expected.index_count, index_count));
+    }
+    let all_decoded = read_back.submeshes.iter()
+        .flat_map(|submesh| submesh.vertices.iter())
+        .all(|vertex| {
+            let decoded = vertex.to_vertex();
+            model.interleaved_vertices.iter().any(|original| vertices_match(&decoded, original))
+        });
+    if !all_decoded {
+        failures.push(format!("{}: compact vertices don't decode to the original vertices", expected.name));
+    }
+}
+
+fn vertices_match(a: &Vertex, b: &Vertex) -> bool {
+    approx_eq(&a.position, &b.position) && approx_eq(&a.normal, &b.normal) && approx_eq(&a.tex_coord, &b.tex_coord)
+}
+
+fn approx_eq(a: &[f32], b: &[f32]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() <= FLOAT_TOLERANCE)
+}